@@ -39,6 +39,29 @@ impl ByteBuffer {
         self
     }
 
+    /// Writes `value` as a MIDI variable-length quantity: seven bits per
+    /// byte, most significant first, with the high bit set on all but the last
+    pub fn put_var_len(&mut self, mut value: u32) -> &mut Self {
+        let mut buf = (value & 0x7f) as u64;
+
+        value >>= 7;
+        while value > 0 {
+            buf <<= 8;
+            buf |= ((value & 0x7f) | 0x80) as u64;
+            value >>= 7;
+        }
+
+        loop {
+            self.buf.push((buf % 256) as u8);
+            if (buf & 0x80) != 0 {
+                buf >>= 8;
+            } else {
+                break;
+            }
+        }
+        self
+    }
+
     pub fn put_string(&mut self, value: &str) -> &mut Self {
         self.buf.extend(value.bytes());
         self
@@ -0,0 +1,59 @@
+//! MIDI channel assignment for ensembles
+
+use crate::constants::midi;
+use crate::part_settings::PartSettings;
+
+/// Hands out MIDI channels 1-16 to performers
+///
+/// Each performer gets a channel of its own while one is free, in order, so
+/// up to nine performers keep the channels the converter has always used.
+/// Channel 10 is held back for percussion. Once the melodic channels run out,
+/// a performer shares the channel of an earlier performer with the same
+/// instrument and settings.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelAllocator {
+    /// Assigned melodic channels and their settings, in channel order
+    assigned: Vec<(u8, PartSettings)>,
+}
+
+impl ChannelAllocator {
+    pub fn new() -> Self {
+        ChannelAllocator::default()
+    }
+
+    /// Channel for a performer playing `instrument` with default settings, or
+    /// `None` when every melodic channel already plays something else
+    pub fn allocate(&mut self, instrument: u8) -> Option<u8> {
+        self.allocate_for(&PartSettings::default().or_instrument(instrument))
+    }
+
+    /// Channel for a performer or part with `settings`, or `None` when every
+    /// melodic channel already plays something else
+    ///
+    /// Program and controllers belong to the channel, so only identical
+    /// settings may share one.
+    pub fn allocate_for(&mut self, settings: &PartSettings) -> Option<u8> {
+        let next = (1..=midi::CHANNEL_COUNT)
+            .filter(|&channel| channel != midi::PERCUSSION_CHANNEL)
+            .find(|channel| !self.assigned.iter().any(|(assigned, _)| assigned == channel));
+        if let Some(channel) = next {
+            self.assigned.push((channel, settings.clone()));
+            return Some(channel);
+        }
+
+        self.assigned
+            .iter()
+            .find(|(_, assigned)| assigned == settings)
+            .map(|&(channel, _)| channel)
+    }
+
+    /// The channel reserved for percussion
+    pub fn percussion(&self) -> u8 {
+        midi::PERCUSSION_CHANNEL
+    }
+
+    /// Melodic channels handed out so far, with their settings
+    pub fn assigned(&self) -> &[(u8, PartSettings)] {
+        &self.assigned
+    }
+}
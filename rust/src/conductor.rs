@@ -0,0 +1,96 @@
+//! Track layouts other than one track per part: a conductor track for
+//! format 1 files and a single merged track for format 0
+
+use crate::constants::meta;
+use crate::options::TempoPolicy;
+use crate::track_event::{EndOfTrack, TrackEvent};
+
+type EventList = Vec<Box<dyn TrackEvent>>;
+
+/// Moves the tempo changes and markers of `tracks` into a conductor track
+/// that starts with `header`, and returns it followed by the part tracks
+///
+/// Tempo changes that disagree are resolved by `policy`. The conductor
+/// track ends with the last part to finish.
+pub fn with_conductor(header: EventList, tracks: Vec<EventList>, policy: TempoPolicy) -> Vec<EventList> {
+    let end = end_time(&tracks);
+    let (mut tempos, mut conductor): (EventList, EventList) =
+        header.into_iter().partition(|event| event.meta_type() == Some(meta::TEMPO));
+    let mut first_part = None;
+    let mut result = Vec::with_capacity(tracks.len() + 1);
+
+    for (index, track) in tracks.into_iter().enumerate() {
+        let mut part = Vec::new();
+        for event in track {
+            match event.meta_type() {
+                Some(meta::TEMPO) => {
+                    if policy == TempoPolicy::Latest || *first_part.get_or_insert(index) == index {
+                        tempos.push(event);
+                    }
+                }
+                Some(meta::MARKER) => conductor.push(event),
+                _ => part.push(event),
+            }
+        }
+        result.push(part);
+    }
+
+    // Later tracks win ties, so keep the last change at each tick
+    tempos.sort_by_key(|event| event.lead_time());
+    let mut resolved: EventList = Vec::with_capacity(tempos.len());
+    for tempo in tempos {
+        if resolved.last().is_some_and(|last| last.lead_time() == tempo.lead_time()) {
+            resolved.pop();
+        }
+        resolved.push(tempo);
+    }
+    conductor.extend(resolved);
+
+    sort_by_time(&mut conductor);
+    let end = conductor.last().map_or(end, |event| event.lead_time().max(end));
+    conductor.push(end_of_track(end));
+    result.insert(0, conductor);
+    result
+}
+
+/// Merges `header` and every event of `tracks` into one track
+///
+/// Track names and the End of Track of each part are dropped; one End of
+/// Track closes the merged track when the last part finishes.
+pub fn merged(header: EventList, tracks: Vec<EventList>, policy: TempoPolicy) -> EventList {
+    let tracks = with_conductor(header, tracks, policy);
+    let end = end_time(&tracks);
+    let mut events: EventList = tracks
+        .into_iter()
+        .flatten()
+        .filter(|event| !matches!(event.meta_type(), Some(meta::END_OF_TRACK | meta::TRACK_NAME)))
+        .collect();
+
+    sort_by_time(&mut events);
+    events.push(end_of_track(end));
+    events
+}
+
+/// Latest End of Track among `tracks`
+fn end_time(tracks: &[EventList]) -> u32 {
+    tracks
+        .iter()
+        .flatten()
+        .filter(|event| event.meta_type() == Some(meta::END_OF_TRACK))
+        .map(|event| event.lead_time())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Stable sort by time, with note-offs ahead of other events at the same
+/// tick so a note ending on a shared channel never cuts one starting there
+fn sort_by_time(events: &mut EventList) {
+    // `status` serializes the event, so read it once per event
+    events.sort_by_cached_key(|event| (event.lead_time(), event.status() & 0xF0 != 0x80));
+}
+
+fn end_of_track(time: u32) -> Box<dyn TrackEvent> {
+    let mut end = Box::new(EndOfTrack::new());
+    end.set_lead_time(time);
+    end
+}
@@ -12,7 +12,13 @@ pub mod timing {
     pub const TICKS_PER_QUARTER_NOTE: u32 = 96;
     
     /// Default timebase for MIDI files
+    ///
+    /// Every tick value in this module, `event_timing` and the MML (`%30`,
+    /// `q%12`) is counted at this timebase and scaled to the one in use.
     pub const DEFAULT_TIMEBASE: u16 = 96;
+
+    /// Largest timebase a MIDI header can hold in ticks per quarter note
+    pub const MAX_TIMEBASE: u16 = 0x7fff;
 }
 
 /// MIDI event timing offsets
@@ -25,6 +31,15 @@ pub mod event_timing {
     
     /// Reverb control event timing offset
     pub const REVERB_CONTROL_OFFSET: u32 = 194;
+
+    /// Chorus control event timing offset
+    pub const CHORUS_CONTROL_OFFSET: u32 = 195;
+
+    /// Expression control event timing offset
+    pub const EXPRESSION_CONTROL_OFFSET: u32 = 196;
+
+    /// Main volume control event timing offset
+    pub const VOLUME_CONTROL_OFFSET: u32 = 197;
     
     /// Default track start time
     pub const TRACK_START_TIME: u32 = 384;
@@ -55,6 +70,27 @@ pub mod mml {
     
     /// Velocity multiplier for volume
     pub const VELOCITY_MULTIPLIER: i32 = 8;
+
+    /// `q` steps; `q8` holds notes for their full length
+    pub const GATE_STEPS: u32 = 8;
+
+    /// Longest `%` tick length, two whole notes
+    pub const MAX_TICK_LENGTH: u32 = 768;
+
+    /// Shortest note division, `l192`
+    pub const MAX_DIVISION: u32 = 192;
+
+    /// Largest `k`/`_` transpose, in semitones either way
+    pub const MAX_TRANSPOSE: i32 = 96;
+
+    /// Passes through `[...]` when no count is written
+    pub const DEFAULT_LOOP_COUNT: u32 = 2;
+
+    /// Most nodes one track may expand to once loops are unrolled
+    pub const MAX_EXPANDED_NODES: usize = 1 << 20;
+
+    /// Characters Mabinogi accepts in one part of a composition scroll
+    pub const PART_CHARACTER_LIMIT: usize = 1200;
 }
 
 /// MIDI format constants
@@ -67,12 +103,21 @@ pub mod midi {
     
     /// MIDI format type (1 = multiple tracks)
     pub const FORMAT_TYPE: u16 = 1;
+
+    /// MIDI format type with every part merged into one track
+    pub const SINGLE_TRACK_FORMAT: u16 = 0;
     
-    /// Tracks per MML (3 tracks per MML string)
+    /// Parts per MML string in the Mabinogi dialect
     pub const TRACKS_PER_MML: u16 = 3;
     
     /// Default tempo in microseconds (500000 = 120 BPM)
     pub const DEFAULT_TEMPO_MICROSECONDS: u32 = 500000;
+
+    /// MIDI channels, numbered from 1
+    pub const CHANNEL_COUNT: u8 = 16;
+
+    /// General MIDI percussion channel
+    pub const PERCUSSION_CHANNEL: u8 = 10;
 }
 
 /// System Exclusive message data
@@ -91,4 +136,28 @@ pub mod control_change {
     
     /// Reverb control change number
     pub const REVERB: u8 = 91;
+
+    /// Chorus control change number
+    pub const CHORUS: u8 = 93;
+
+    /// Expression control change number
+    pub const EXPRESSION: u8 = 11;
+
+    /// Main volume control change number
+    pub const MAIN_VOLUME: u8 = 7;
+}
+
+/// Meta event types, the byte after 0xFF
+pub mod meta {
+    /// Track name (FF 03)
+    pub const TRACK_NAME: u8 = 0x03;
+
+    /// Marker (FF 06)
+    pub const MARKER: u8 = 0x06;
+
+    /// End of track (FF 2F)
+    pub const END_OF_TRACK: u8 = 0x2f;
+
+    /// Set tempo (FF 51)
+    pub const TEMPO: u8 = 0x51;
 }
@@ -1,5 +1,7 @@
 //! Error types for YKS Converter
 
+use crate::midi::ReadError;
+use crate::mml::diagnostic::Diagnostic;
 use std::fmt;
 
 /// Errors that can occur during MML to MIDI conversion
@@ -11,15 +13,40 @@ pub enum ConversionError {
         inst_count: usize,
     },
     /// Regex compilation failed
+    #[deprecated(note = "no longer produced; MML is read by `mml::lexer` instead of regexes")]
     RegexCompileFailed(String),
     /// MML parsing failed
-    MmlParseFailed(String),
+    MmlParseFailed {
+        /// Index of the MML string that failed
+        index: usize,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Strict mode rejected tokens that would not play as written
+    StrictModeViolation {
+        /// Index of the MML string that was rejected
+        index: usize,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Every melodic MIDI channel is taken by another instrument or settings
+    ChannelsExhausted {
+        /// Index of the MML string that found no channel
+        index: usize,
+        /// Instrument of the performer or part
+        instrument: u8,
+    },
+    /// Timebase of zero or above 32767 ticks per quarter note
+    InvalidTimebase(u16),
+    /// Instrument name not in the catalogue
+    UnknownInstrument(String),
     /// Empty track list
     EmptyTrackList,
     /// Event conversion failed
     EventConversionFailed(String),
+    /// MIDI file to convert back to MML is malformed or truncated
+    MidiReadFailed(ReadError),
 }
 
+#[allow(deprecated)]
 impl fmt::Display for ConversionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -29,8 +56,22 @@ impl fmt::Display for ConversionError {
             ConversionError::RegexCompileFailed(msg) => {
                 write!(f, "Regex compile failed: {}", msg)
             }
-            ConversionError::MmlParseFailed(msg) => {
-                write!(f, "MML parsing failed: {}", msg)
+            ConversionError::MmlParseFailed { index, diagnostics } => {
+                write!(f, "MML parsing failed (MML {}): ", index + 1)?;
+                write_diagnostics(f, diagnostics)
+            }
+            ConversionError::StrictModeViolation { index, diagnostics } => {
+                write!(f, "Strict mode rejected MML {}: ", index + 1)?;
+                write_diagnostics(f, diagnostics)
+            }
+            ConversionError::ChannelsExhausted { index, instrument } => {
+                write!(f, "No MIDI channel left for MML {} (instrument {})", index + 1, instrument)
+            }
+            ConversionError::InvalidTimebase(timebase) => {
+                write!(f, "Invalid timebase {}: must be 1-32767 ticks per quarter note", timebase)
+            }
+            ConversionError::UnknownInstrument(name) => {
+                write!(f, "Unknown instrument: {}", name)
             }
             ConversionError::EmptyTrackList => {
                 write!(f, "Track is empty")
@@ -38,8 +79,21 @@ impl fmt::Display for ConversionError {
             ConversionError::EventConversionFailed(msg) => {
                 write!(f, "Event Convert error: {}", msg)
             }
+            ConversionError::MidiReadFailed(err) => {
+                write!(f, "MIDI read failed: {}", err)
+            }
         }
     }
 }
 
 impl std::error::Error for ConversionError {}
+
+fn write_diagnostics(f: &mut fmt::Formatter<'_>, diagnostics: &[Diagnostic]) -> fmt::Result {
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            write!(f, "; ")?;
+        }
+        write!(f, "{}", diagnostic)?;
+    }
+    Ok(())
+}
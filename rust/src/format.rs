@@ -0,0 +1,134 @@
+//! Canonical formatting of MML
+//!
+//! Parts are written back token by token from the lexer: lowercase, without
+//! whitespace, with `#` sharps as `+`. Optionally a space marks each bar
+//! line. None of this reaches `parse_track`, so the MIDI stays the same.
+
+use crate::constants::timing;
+use crate::mf2tt2mf::Mf2tt2mf;
+use crate::mml::ast::{Track, TokenKind};
+use crate::mml::{lex_with, parse_with, Diagnostic, Dialect};
+use crate::options::ConversionOptions;
+
+/// Rewrites MML in one canonical spelling
+///
+/// # Examples
+///
+/// ```
+/// use yks_converter::format::Formatter;
+///
+/// let mut formatter = Formatter::new();
+/// assert_eq!(formatter.format("MML@T120 L8 C#DEF GAB>C D,,;").unwrap(), "MML@t120l8c+defgab>cd,,;");
+///
+/// formatter.set_bar_lines(Some((4, 4)));
+/// assert_eq!(formatter.format("MML@T120 L8 C#DEF GAB>C D,,;").unwrap(), "MML@t120l8c+defgab>c d,,;");
+/// ```
+pub struct Formatter {
+    dialect: Dialect,
+    bar_lines: Option<(u8, u8)>,
+}
+
+impl Formatter {
+    pub fn new() -> Self {
+        Formatter {
+            dialect: Dialect::default(),
+            bar_lines: None,
+        }
+    }
+
+    /// Syntax the MML is read with, Mabinogi's by default
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    /// Time signature whose measures are separated by a space, or `None`
+    /// (the default) for no spaces at all
+    ///
+    /// Bar lines only go between the commands of a part, never inside a
+    /// loop, chord or tuplet.
+    pub fn set_bar_lines(&mut self, time_signature: Option<(u8, u8)>) {
+        self.bar_lines = time_signature;
+    }
+
+    /// `mml` in canonical form, or why it does not parse
+    pub fn format(&self, mml: &str) -> Result<String, Vec<Diagnostic>> {
+        let score = parse_with(mml, &self.dialect)?;
+        // One conversion times every command, at 96 ticks per quarter note
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { dialect: self.dialect.clone(), lead_in: 0, ..Default::default() });
+        if self.bar_lines.is_some() {
+            parser.from_score(mml, &score);
+        }
+        let parts: Vec<String> = score.tracks.iter().enumerate()
+            .map(|(i, track)| self.format_part(mml, track, parser.node_starts(i)))
+            .collect();
+        Ok(format!("MML@{};", parts.join(",")))
+    }
+
+    /// Whether `mml` is already in canonical form
+    pub fn is_formatted(&self, mml: &str) -> Result<bool, Vec<Diagnostic>> {
+        Ok(self.format(mml)? == mml)
+    }
+
+    fn format_part(&self, mml: &str, track: &Track, starts: &[u32]) -> String {
+        let bars = self.bar_offsets(track, starts);
+        let mut result = String::new();
+        let mut written = track.span.start;
+        // Skipped text can come after the token it sits inside
+        let mut tokens = lex_with(track.span.text(mml), track.span.start, &self.dialect);
+        tokens.sort_by_key(|token| token.span.start);
+        for token in tokens {
+            if token.span.end <= written {
+                continue;
+            }
+            // Anything between tokens reaches the converter as nothing
+            let start = token.span.start.max(written);
+            let unread: String = mml[written..start].chars().filter(|c| !c.is_whitespace()).collect();
+            result.push_str(&unread.to_ascii_lowercase());
+            written = token.span.end;
+
+            if bars.contains(&start) {
+                result.push(' ');
+            }
+            let text = &mml[start..token.span.end];
+            match token.kind {
+                TokenKind::Text { .. } => result.push_str(text),
+                TokenKind::Note { .. } => result.extend(canonical(text).map(|c| if c == '#' { '+' } else { c })),
+                _ => result.extend(canonical(text)),
+            }
+        }
+        let unread: String = mml[written..track.span.end].chars().filter(|c| !c.is_whitespace()).collect();
+        result.push_str(&unread.to_ascii_lowercase());
+        result
+    }
+
+    /// Offsets of the commands that start a new measure, after the first
+    fn bar_offsets(&self, track: &Track, starts: &[u32]) -> Vec<usize> {
+        let Some((numerator, denominator)) = self.bar_lines else { return Vec::new() };
+        let measure = timing::TICKS_PER_WHOLE_NOTE * numerator as u32 / denominator.max(1) as u32;
+        if measure == 0 {
+            return Vec::new();
+        }
+
+        let mut offsets = Vec::new();
+        let mut next_bar = measure;
+        for (node, &time) in track.nodes.iter().zip(starts) {
+            if time >= next_bar {
+                offsets.push(node.span.start);
+                next_bar = (time / measure + 1) * measure;
+            }
+        }
+        offsets
+    }
+}
+
+impl Default for Formatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `text` lowercase and without whitespace
+fn canonical(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_ascii_lowercase())
+}
@@ -0,0 +1,184 @@
+//! Mabinogi instruments and their General MIDI programs
+
+use crate::errors::ConversionError;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// An instrument playable in Mabinogi
+///
+/// Converts into the GM program number (`u8`) that `YksConverter` and
+/// `PartSettings` take, and parses from names such as `"lute"` or
+/// `"electric guitar"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instrument {
+    Lute,
+    Ukulele,
+    Mandolin,
+    Whistle,
+    Roncadora,
+    Flute,
+    Chalumeau,
+    Tuba,
+    Lyre,
+    ElectricGuitar,
+    Piano,
+    Violin,
+    Cello,
+    Xylophone,
+    /// The drum kit; plays on the percussion channel
+    Drums,
+}
+
+impl Instrument {
+    /// Every instrument, in catalogue order
+    pub const ALL: [Instrument; 15] = [
+        Instrument::Lute,
+        Instrument::Ukulele,
+        Instrument::Mandolin,
+        Instrument::Whistle,
+        Instrument::Roncadora,
+        Instrument::Flute,
+        Instrument::Chalumeau,
+        Instrument::Tuba,
+        Instrument::Lyre,
+        Instrument::ElectricGuitar,
+        Instrument::Piano,
+        Instrument::Violin,
+        Instrument::Cello,
+        Instrument::Xylophone,
+        Instrument::Drums,
+    ];
+
+    /// Name as shown in game
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instrument::Lute => "Lute",
+            Instrument::Ukulele => "Ukulele",
+            Instrument::Mandolin => "Mandolin",
+            Instrument::Whistle => "Whistle",
+            Instrument::Roncadora => "Roncadora",
+            Instrument::Flute => "Flute",
+            Instrument::Chalumeau => "Chalumeau",
+            Instrument::Tuba => "Tuba",
+            Instrument::Lyre => "Lyre",
+            Instrument::ElectricGuitar => "Electric Guitar",
+            Instrument::Piano => "Piano",
+            Instrument::Violin => "Violin",
+            Instrument::Cello => "Cello",
+            Instrument::Xylophone => "Xylophone",
+            Instrument::Drums => "Drums",
+        }
+    }
+
+    /// General MIDI program, counted from 0 as `ProgramChange` sends it
+    pub fn program(&self) -> u8 {
+        match self {
+            Instrument::Lute => 24,           // Acoustic Guitar (nylon)
+            Instrument::Ukulele => 24,        // Acoustic Guitar (nylon)
+            Instrument::Mandolin => 25,       // Acoustic Guitar (steel)
+            Instrument::Whistle => 78,        // Whistle
+            Instrument::Roncadora => 74,      // Recorder
+            Instrument::Flute => 73,          // Flute
+            Instrument::Chalumeau => 71,      // Clarinet
+            Instrument::Tuba => 58,           // Tuba
+            Instrument::Lyre => 46,           // Orchestral Harp
+            Instrument::ElectricGuitar => 29, // Overdriven Guitar
+            Instrument::Piano => 0,           // Acoustic Grand Piano
+            Instrument::Violin => 40,         // Violin
+            Instrument::Cello => 42,          // Cello
+            Instrument::Xylophone => 13,      // Xylophone
+            Instrument::Drums => 0,           // Standard Kit
+        }
+    }
+
+    /// Lowest and highest MIDI note the instrument plays (C4 is 60)
+    ///
+    /// Notes outside it are reported as warnings when the instrument is set
+    /// with `YksConverter::set_instrument` or `PartSettings::from`. The drum
+    /// kit's range is of GM drum notes, so its parts are not checked.
+    pub fn range(&self) -> RangeInclusive<u8> {
+        match self {
+            Instrument::Lute => 36..=96,
+            Instrument::Ukulele => 48..=84,
+            Instrument::Mandolin => 55..=96,
+            Instrument::Whistle => 60..=108,
+            Instrument::Roncadora => 60..=96,
+            Instrument::Flute => 60..=108,
+            Instrument::Chalumeau => 48..=96,
+            Instrument::Tuba => 24..=72,
+            Instrument::Lyre => 36..=96,
+            Instrument::ElectricGuitar => 40..=88,
+            Instrument::Piano => 21..=108,
+            Instrument::Violin => 55..=103,
+            Instrument::Cello => 36..=84,
+            Instrument::Xylophone => 65..=108,
+            Instrument::Drums => 35..=81,
+        }
+    }
+
+    /// Octave (`o`) whose notes sit in the middle of the instrument's range
+    ///
+    /// For writing new parts: `o3` suits the tuba and `o6` the whistle.
+    /// Conversion does not use it; every part starts at `o4`, as in game, so
+    /// MML plays at the same pitch on any instrument.
+    pub fn default_octave(&self) -> u8 {
+        match self {
+            Instrument::Lute => 4,
+            Instrument::Ukulele => 4,
+            Instrument::Mandolin => 5,
+            Instrument::Whistle => 6,
+            Instrument::Roncadora => 5,
+            Instrument::Flute => 6,
+            Instrument::Chalumeau => 5,
+            Instrument::Tuba => 3,
+            Instrument::Lyre => 4,
+            Instrument::ElectricGuitar => 4,
+            Instrument::Piano => 4,
+            Instrument::Violin => 5,
+            Instrument::Cello => 4,
+            Instrument::Xylophone => 6,
+            Instrument::Drums => 4,
+        }
+    }
+
+    /// `true` for the drum kit, which `YksConverter::set_instrument` and
+    /// `PartSettings::from` put on the percussion channel
+    pub fn is_percussion(&self) -> bool {
+        *self == Instrument::Drums
+    }
+}
+
+impl fmt::Display for Instrument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl From<Instrument> for u8 {
+    fn from(instrument: Instrument) -> u8 {
+        instrument.program()
+    }
+}
+
+impl FromStr for Instrument {
+    type Err = ConversionError;
+
+    /// Case-insensitive; spaces, `-` and `_` are ignored, so `"Electric
+    /// Guitar"`, `"electric-guitar"` and `"electricguitar"` all match.
+    /// `"drum"` and `"drum kit"` name the drums.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let key: String = name
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '_'))
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if matches!(key.as_str(), "drum" | "drumkit") {
+            return Ok(Instrument::Drums);
+        }
+        Instrument::ALL
+            .into_iter()
+            .find(|instrument| instrument.name().replace(' ', "").to_ascii_lowercase() == key)
+            .ok_or_else(|| ConversionError::UnknownInstrument(name.to_string()))
+    }
+}
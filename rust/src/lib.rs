@@ -1,14 +1,37 @@
 pub mod byte_buffer;
+pub mod channel_allocator;
+pub mod conductor;
 pub mod track_event;
 pub mod track_builder;
 pub mod mf2tt2mf;
 pub mod yks_converter;
 pub mod errors;
+pub mod format;
+pub mod instruments;
 pub mod constants;
+pub mod midi;
+pub mod midi_to_mml;
+pub mod mml;
+pub mod optimize;
+pub mod options;
+pub mod part_settings;
+pub mod percussion;
+pub mod report;
+pub mod voices;
 
 pub use yks_converter::YksConverter;
 pub use byte_buffer::ByteBuffer;
+pub use channel_allocator::ChannelAllocator;
 pub use errors::ConversionError;
+pub use format::Formatter;
+pub use instruments::Instrument;
+pub use midi_to_mml::MidiToMml;
+pub use optimize::Optimizer;
+pub use options::{ConversionOptions, OutputFormat, TempoPolicy};
+pub use part_settings::PartSettings;
+pub use percussion::DrumMap;
+pub use report::{ConversionReport, ConversionWarning};
+pub use voices::VoiceStrategy;
 
 #[cfg(test)]
 mod tests {
@@ -22,7 +45,7 @@ mod tests {
         
         let result = parser.from_mml(mml);
         
-        assert!(result, "MML regex parsing should succeed for valid input");
+        assert!(result.is_ok(), "MML regex parsing should succeed for valid input");
     }
 
     #[test]
@@ -33,7 +56,7 @@ mod tests {
         let result = parser.from_mml(mml);
         
         // Currently this passes but should match C++ behavior
-        println!("Result for failing MML: {}", result);
+        println!("Result for failing MML: {:?}", result);
     }
 
     #[test] 
@@ -107,7 +130,7 @@ mod tests {
         let mml = "MML@c,,;"; // Simple single note C with 3 tracks
         
         let result = parser.from_mml(mml);
-        assert!(result, "Should parse single note C successfully");
+        assert!(result.is_ok(), "Should parse single note C successfully");
         
         let track_events = parser.build();
         assert_eq!(track_events.len(), 3, "Should have three tracks (c, empty, empty)");
@@ -136,7 +159,7 @@ mod tests {
         let mml = "MML@cdef,,;"; // Note sequence C-D-E-F
         
         let result = parser.from_mml(mml);
-        assert!(result, "Should parse note sequence CDEF successfully");
+        assert!(result.is_ok(), "Should parse note sequence CDEF successfully");
         
         let track_events = parser.build();
         let first_track = &track_events[0];
@@ -162,7 +185,7 @@ mod tests {
         let mml = "MML@c4.,,;"; // Dotted quarter note
         
         let result = parser.from_mml(mml);
-        assert!(result, "Should parse dotted quarter note");
+        assert!(result.is_ok(), "Should parse dotted quarter note");
         
         let tracks = parser.build();
         let first_track = &tracks[0];
@@ -199,7 +222,7 @@ mod tests {
         let mml = "MML@l8cde,,;"; // Length 8 (eighth note) followed by notes
         
         let result = parser.from_mml(mml);
-        assert!(result, "Should parse length token l8 successfully");
+        assert!(result.is_ok(), "Should parse length token l8 successfully");
         
         let track_events = parser.build();
         let first_track = &track_events[0];
@@ -246,7 +269,7 @@ mod tests {
         let mml = "MML@c,,;"; // Simple single note
         
         let result = parser.from_mml(mml);
-        assert!(result, "Should parse simple note");
+        assert!(result.is_ok(), "Should parse simple note");
         
         let tracks = parser.build();
         let first_track = &tracks[0];
@@ -275,4 +298,974 @@ mod tests {
             println!("\nMIDI hex for single 'c': {}", rust_hex);
         }
     }
+
+    #[test]
+    fn should_lex_typed_tokens_with_spans() {
+        use crate::mml::ast::{Accidental, Duration, OctaveShift, Span, TokenKind};
+
+        let tokens = mml::lex("l8 c+4.&d>n60");
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind.clone()).collect();
+
+        assert_eq!(kinds, vec![
+            TokenKind::Length { value: Some(Duration::Division(8)), dots: 0 },
+            TokenKind::Note { note: 'c', accidental: Some(Accidental::Sharp), length: Some(Duration::Division(4)), dots: 1 },
+            TokenKind::Tie,
+            TokenKind::Note { note: 'd', accidental: None, length: None, dots: 0 },
+            TokenKind::OctaveShift(OctaveShift::Up),
+            TokenKind::AbsoluteNote(Some(60)),
+        ]);
+        assert_eq!(tokens[1].span, Span::new(3, 7), "Note span should cover c+4.");
+        assert_eq!(tokens[2].span, Span::new(7, 8), "Tie span should cover &");
+    }
+
+    #[test]
+    fn should_read_token_values_like_parse_track() {
+        use crate::mml::ast::{Duration, TokenKind};
+
+        // Leading zero and accidentals cut control values short, as the C++ regex does
+        let tokens = mml::lex("l08o+5c08r+4.");
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind.clone()).collect();
+
+        assert_eq!(kinds, vec![
+            TokenKind::Length { value: Some(Duration::Division(0)), dots: 0 },
+            TokenKind::Skipped,
+            TokenKind::Octave(None),
+            TokenKind::Skipped,
+            TokenKind::Note { note: 'c', accidental: None, length: Some(Duration::Division(8)), dots: 0 },
+            TokenKind::Rest { length: None, dots: 0 },
+            TokenKind::Skipped,
+        ]);
+    }
+
+    #[test]
+    fn should_parse_tracks_with_spans_into_original_string() {
+        use crate::mml::ast::NodeKind;
+
+        let source = "MML@t120 C4&c, l16d,;";
+        let score = mml::parse(source).expect("MML should parse");
+
+        assert_eq!(score.tracks.len(), 3);
+        assert_eq!(score.tracks[0].span.text(source), "t120 C4&c");
+        assert_eq!(score.tracks[1].span.text(source), "l16d");
+        assert!(score.tracks[2].is_empty(), "Third track should be empty");
+
+        let first = &score.tracks[0].nodes;
+        assert_eq!(first.len(), 3, "Tie should fold into the note it follows");
+        assert_eq!(first[1].span.text(source), "C4&");
+        assert!(matches!(first[1].kind, NodeKind::Note { note: 'c', tied: true, .. }));
+        assert_eq!(score.tracks[1].nodes[1].span.text(source), "d");
+    }
+
+    #[test]
+    fn should_report_unknown_characters_with_position() {
+        use crate::mml::DiagnosticCode;
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        let diagnostics = parser.from_mml("MML@cde,ggxz,c$;").unwrap_err();
+
+        assert_eq!(diagnostics.len(), 2, "Each run of unknown characters is reported once");
+        assert_eq!(diagnostics[0].code, DiagnosticCode::UnknownCharacter);
+        assert_eq!(diagnostics[0].track, Some(1));
+        assert_eq!(diagnostics[0].offset, 10);
+        assert_eq!(diagnostics[0].text, "xz");
+        assert_eq!(diagnostics[1].track, Some(2));
+        assert_eq!(diagnostics[1].text, "$");
+    }
+
+    #[test]
+    fn should_report_mml_shape_errors() {
+        use crate::mml::DiagnosticCode;
+
+        let codes = |mml: &str| -> Vec<DiagnosticCode> {
+            mml::parse(mml).unwrap_err().iter().map(|d| d.code).collect()
+        };
+
+        assert_eq!(codes("t120cde,,;"), vec![DiagnosticCode::MissingPrefix]);
+        assert_eq!(codes("MML@cde,"), vec![DiagnosticCode::MissingTerminator]);
+        assert_eq!(codes("MML@cde,e,g"), vec![DiagnosticCode::MissingTerminator]);
+        assert!(mml::parse("MML@cde,,;").is_ok());
+
+        let diagnostics = mml::parse("MML@c,d,e,f;").unwrap_err();
+        assert_eq!(diagnostics[0].code, DiagnosticCode::WrongPartCount);
+        assert_eq!(diagnostics[0].offset, 9, "Should point at the surplus comma");
+        assert_eq!(diagnostics[0].code.as_str(), "wrong-part-count");
+    }
+
+    #[test]
+    fn should_carry_diagnostics_in_conversion_error() {
+        let converter = YksConverter::new_multi(
+            vec!["MML@c,,;".to_string(), "MML@c,?,;".to_string()],
+            vec![1, 1],
+        );
+
+        match converter.to_buffer_result() {
+            Err(ConversionError::MmlParseFailed { index, diagnostics }) => {
+                assert_eq!(index, 1, "Second MML string should be blamed");
+                assert_eq!(diagnostics[0].offset, 6);
+            }
+            other => panic!("Expected MmlParseFailed, got {:?}", other.map(|b| b.size())),
+        }
+    }
+
+    #[test]
+    fn should_report_clamped_and_ignored_tokens() {
+        use crate::mml::{DiagnosticCode, Severity};
+
+        let converter = YksConverter::new("MML@v20l0c,l256n200,o12c<<<<<<<<<<<<<;".to_string(), 1);
+        let (_, report) = converter.to_buffer_with_report().unwrap();
+
+        let found: Vec<_> = report.warnings.iter()
+            .map(|w| (w.diagnostic.track.unwrap(), w.diagnostic.code, w.diagnostic.text.as_str()))
+            .collect();
+        assert_eq!(found, vec![
+            (0, DiagnosticCode::VolumeClamped, "v20"),
+            (0, DiagnosticCode::LengthIgnored, "l0"),
+            (1, DiagnosticCode::LengthIgnored, "l256"),
+            (1, DiagnosticCode::NoteNumberOutOfRange, "n200"),
+            (2, DiagnosticCode::OctaveOutOfRange, "o12"),
+            (2, DiagnosticCode::NoteWrapped, "c"),
+            (2, DiagnosticCode::OctaveShiftClamped, "<"),
+        ]);
+        assert_eq!(report.warnings[0].diagnostic.message, "volume 20 was clamped to 15");
+        assert_eq!(report.warnings[0].diagnostic.offset, 4);
+        assert!(report.warnings.iter().all(|w| w.diagnostic.severity() == Severity::Warning));
+    }
+
+    #[test]
+    fn should_report_text_the_converter_skips() {
+        let source = "c4..r4&l+8<5";
+        let track = mml::parse_track(source).unwrap();
+        let skipped: Vec<_> = track.skipped.iter().map(|span| span.text(source)).collect();
+
+        assert_eq!(skipped, vec![".", "&", "+8", "5"]);
+
+        let converter = YksConverter::new("MML@t120l4cde,,;".to_string(), 1);
+        let (_, report) = converter.to_buffer_with_report().unwrap();
+        assert!(report.is_clean(), "Well-formed MML should produce no warnings");
+    }
+
+    #[test]
+    fn should_reject_out_of_range_input_in_strict_mode() {
+        use crate::mml::DiagnosticCode;
+
+        let mut converter = YksConverter::new("MML@t120l4c>>>>>>c,,;".to_string(), 1);
+        assert!(converter.to_buffer_result().is_ok(), "Lenient mode should clamp and convert");
+
+        converter.set_options(ConversionOptions { strict: true, ..Default::default() });
+        match converter.to_buffer_result() {
+            Err(ConversionError::StrictModeViolation { index, diagnostics }) => {
+                assert_eq!(index, 0);
+                assert_eq!(diagnostics[0].code, DiagnosticCode::OctaveShiftClamped);
+                assert_eq!(diagnostics[0].offset, 16, "Should point at the sixth '>'");
+                assert_eq!(diagnostics[1].code, DiagnosticCode::NoteWrapped, "o9c lies above the note range");
+            }
+            other => panic!("Expected StrictModeViolation, got {:?}", other.map(|b| b.size())),
+        }
+    }
+
+    #[test]
+    fn should_accept_clean_input_in_strict_mode() {
+        let mut converter = YksConverter::new("MML@t190l8cdefgab>c4.,l8<cdefgab>c4.,l8>cdefgab>c4.;".to_string(), 1);
+        let lenient = converter.to_buffer_result().unwrap();
+
+        converter.set_options(ConversionOptions { strict: true, ..Default::default() });
+        let strict = converter.to_buffer_result().expect("Clean MML should pass strict mode");
+
+        assert_eq!(strict.as_slice(), lenient.as_slice());
+    }
+
+    #[test]
+    fn should_accept_fewer_parts_and_count_header_tracks() {
+        let converter = YksConverter::new("MML@t120cde;".to_string(), 1);
+        let buffer = converter.to_buffer_result().expect("A single part should convert");
+        let bytes = buffer.as_slice();
+
+        assert_eq!(u16::from_be_bytes([bytes[10], bytes[11]]), 1, "Header should count one track");
+        assert_eq!(bytes.windows(4).filter(|w| w == b"MTrk").count(), 1);
+
+        let converter = YksConverter::new_multi(
+            vec!["MML@c,e;".to_string(), "MML@c,,;".to_string()],
+            vec![1, 1],
+        );
+        let bytes = converter.to_buffer_result().unwrap().to_vec();
+        assert_eq!(u16::from_be_bytes([bytes[10], bytes[11]]), 5);
+    }
+
+    #[test]
+    fn should_limit_parts_per_dialect() {
+        use crate::mml::Dialect;
+
+        let mml = "MML@c,e,g,>c,>e;";
+        let mut converter = YksConverter::new(mml.to_string(), 1);
+        match converter.to_buffer_result() {
+            Err(ConversionError::MmlParseFailed { diagnostics, .. }) => {
+                assert_eq!(diagnostics[0].message, "expected at most 3 comma-separated parts, found 5");
+            }
+            other => panic!("Expected MmlParseFailed, got {:?}", other.map(|b| b.size())),
+        }
+
+        converter.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        let bytes = converter.to_buffer_result().expect("Extended dialect allows five parts").to_vec();
+        assert_eq!(u16::from_be_bytes([bytes[10], bytes[11]]), 5);
+
+        let diagnostics = mml::parse_with(mml, &Dialect { max_parts: 4, ..Dialect::mabinogi() }).unwrap_err();
+        assert_eq!(diagnostics[0].offset, 12, "Should point at the fourth comma");
+
+        // No parts allowed, on unterminated input
+        let diagnostics = mml::parse_with("MML@c", &Dialect { max_parts: 0, ..Dialect::mabinogi() }).unwrap_err();
+        assert!(diagnostics.iter().any(|d| d.code == mml::DiagnosticCode::WrongPartCount && d.text.is_empty()));
+    }
+
+    #[test]
+    fn should_unroll_nested_loops_with_breaks() {
+        use crate::mml::ast::NodeKind;
+        use crate::mml::Dialect;
+
+        let score = mml::parse_with("MML@[c[d]3:e]2;", &Dialect::extended()).unwrap();
+        let track = &score.tracks[0];
+        match &track.nodes[0].kind {
+            NodeKind::Loop { body, count } => {
+                assert_eq!(*count, Some(2));
+                assert_eq!(body.len(), 4, "Loop body should hold c, the inner loop, ':' and e");
+            }
+            other => panic!("Expected a loop node, got {:?}", other),
+        }
+
+        let played: String = track
+            .expanded()
+            .iter()
+            .map(|node| match node.kind {
+                NodeKind::Note { note, .. } => note,
+                _ => '?',
+            })
+            .collect();
+        assert_eq!(played, "cdddecddd", "The last pass should stop at ':'");
+    }
+
+    #[test]
+    fn should_time_loops_like_written_out_notes() {
+        use crate::mml::Dialect;
+
+        let mut looped = YksConverter::new("MML@t90l8[c:d&]3 [e]r[]0,[[>c<]],;".to_string(), 1);
+        looped.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        let written = YksConverter::new("MML@t90l8cd&cd&c eer,>c<>c<>c<>c<,;".to_string(), 1);
+
+        assert_eq!(
+            looped.to_buffer_result().unwrap().to_vec(),
+            written.to_buffer_result().unwrap().to_vec(),
+            "Unrolled loops should produce the same NoteOn/NoteOff timing"
+        );
+    }
+
+    #[test]
+    fn should_report_loop_errors() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let diagnostics = mml::parse("MML@[c]2;").unwrap_err();
+        assert_eq!(diagnostics[0].code, DiagnosticCode::UnknownCharacter, "Mabinogi has no loops");
+
+        let diagnostics = mml::parse_with("MML@c]2,[d;", &Dialect::extended()).unwrap_err();
+        let found: Vec<_> = diagnostics.iter().map(|d| (d.track, d.offset, d.text.as_str())).collect();
+        assert_eq!(found, vec![(Some(0), 5, "]2"), (Some(1), 8, "[")]);
+        assert!(diagnostics.iter().all(|d| d.code == DiagnosticCode::UnbalancedLoop));
+
+        let diagnostics = mml::parse_with("MML@[[[[[c]99]99]99]99]99;", &Dialect::extended()).unwrap_err();
+        assert_eq!(diagnostics[0].code, DiagnosticCode::LoopTooLong);
+    }
+
+    #[test]
+    fn should_sound_chord_notes_together() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        parser.from_mml("MML@l4'ceg'&'cf+a' 'c>c'8 c;").expect("Chords should parse in the extended dialect");
+
+        let notes: Vec<String> = parser.build()[0]
+            .iter()
+            .filter(|event| event.value().starts_with("Note"))
+            .map(|event| format!("{} {}", event.lead_time(), event.value()))
+            .collect();
+        let expected: Vec<String> = [
+            (384, "On", 60), (384, "On", 64), (384, "On", 67),
+            (480, "Off", 64), (480, "Off", 67), (480, "On", 66), (480, "On", 69),
+            (576, "Off", 60), (576, "Off", 66), (576, "Off", 69),
+            (576, "On", 60), (576, "On", 72), (624, "Off", 60), (624, "Off", 72),
+            (624, "On", 72), (720, "Off", 72),
+        ]
+        .iter()
+        .map(|(time, kind, note)| {
+            let velocity = if *kind == "On" { 64 } else { 0 };
+            format!("{} Note{}: ch=1, note={}, vel={}", time, kind, note, velocity)
+        })
+        .collect();
+        assert_eq!(notes, expected, "Tied chords should hold shared pitches and release the rest");
+
+        let diagnostics = mml::parse_with("MML@'c r'4 '<>'4 'e;", &Dialect::extended()).unwrap_err();
+        let found: Vec<_> = diagnostics.iter().map(|d| (d.code, d.text.as_str())).collect();
+        assert_eq!(found, vec![
+            (DiagnosticCode::MalformedChord, "r"),
+            (DiagnosticCode::MalformedChord, "'<>'4"),
+            (DiagnosticCode::MalformedChord, "'"),
+        ]);
+        assert_eq!(mml::parse("MML@'ceg';").unwrap_err()[0].code, DiagnosticCode::UnknownCharacter);
+    }
+
+    #[test]
+    fn should_shorten_notes_by_gate_time_except_across_ties() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        parser.from_mml("MML@l4 q4c q%90d8 e&e q8f q9q;").expect("Gate times should parse in the extended dialect");
+
+        let notes: Vec<String> = parser.build()[0]
+            .iter()
+            .filter(|event| event.value().starts_with("Note"))
+            .map(|event| format!("{} {}", event.lead_time(), &event.value()[..event.value().find(':').unwrap()]))
+            .collect();
+        assert_eq!(notes, vec![
+            "384 NoteOn", "432 NoteOff",  // q4: half of 96 ticks
+            "480 NoteOn", "481 NoteOff",  // cut past the length keeps one tick
+            "528 NoteOn", "630 NoteOff",  // the tie holds, only the last e is cut
+            "720 NoteOn", "816 NoteOff",  // q8 is the full length
+        ]);
+
+        let warnings: Vec<_> = parser.warnings().iter().map(|w| (w.code, w.text.as_str())).collect();
+        assert_eq!(warnings, vec![(DiagnosticCode::GateIgnored, "q9"), (DiagnosticCode::GateIgnored, "q")]);
+    }
+
+    #[test]
+    fn should_transpose_notes_from_mml_and_options() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let pitches = |mml: &str, transpose: i32| -> Vec<String> {
+            let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+            parser.set_options(ConversionOptions { dialect: Dialect::extended(), transpose, ..Default::default() });
+            parser.from_mml(mml).expect("Transpose commands should parse in the extended dialect");
+            parser.build()[0]
+                .iter()
+                .filter(|event| event.value().starts_with("NoteOn"))
+                .map(|event| event.value()["NoteOn: ch=1, note=".len()..].split(',').next().unwrap().to_string())
+                .collect()
+        };
+
+        // c, k2 c, _-3 c, k0 n60, then o8 k+12 b wraps down two octaves
+        assert_eq!(pitches("MML@c k2c _-3c k0n60 o8k+12b;", 0), vec!["60", "62", "59", "72", "107"]);
+        assert_eq!(pitches("MML@c k2c;", -12), vec!["48", "50"], "The option should add to the MML transpose");
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        parser.from_mml("MML@k200c _c;").unwrap();
+        let warnings: Vec<_> = parser.warnings().iter().map(|w| (w.code, w.message.as_str())).collect();
+        assert_eq!(warnings, vec![
+            (DiagnosticCode::TransposeClamped, "transpose 200 was clamped to 96"),
+            (DiagnosticCode::NoteWrapped, "pitch moved down 4 octaves into range"),
+            (DiagnosticCode::TransposeIgnored, "'_' without a value was ignored"),
+            (DiagnosticCode::NoteWrapped, "pitch moved down 4 octaves into range"),
+        ]);
+    }
+
+    #[test]
+    fn should_time_tuplets_and_odd_lengths_exactly() {
+        use crate::mml::Dialect;
+
+        let times = |mml: &str| -> Vec<(u32, bool)> {
+            let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+            parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+            parser.from_mml(mml).expect("Exact lengths should parse in the extended dialect");
+            parser.build()[0]
+                .iter()
+                .filter(|event| event.value().starts_with("Note"))
+                .map(|event| (event.lead_time(), event.value().starts_with("NoteOn")))
+                .collect()
+        };
+        let note_ons = |mml: &str| -> Vec<u32> {
+            times(mml).into_iter().filter(|(_, on)| *on).map(|(time, _)| time).collect()
+        };
+
+        // Seven c7 fill exactly one whole note, so the next bar starts on 864
+        assert_eq!(note_ons("MML@{ceg}4 c7c7c7c7c7c7c7 c4..r%30c%30.d;"), vec![
+            384, 416, 448, // triplet of 32 ticks each
+            480, 534, 589, 644, 699, 754, 809,
+            864, // c4.. is 96 + 48 + 24
+            1062, 1107, // after r%30, c%30. lasts 45 ticks
+        ]);
+        assert_eq!(times("MML@{ceg}4 c7c7c7c7c7c7c7 c4..r%30c%30.d;").last(), Some(&(1203, false)));
+
+        // Inner tuplets take one share of the outer one
+        assert_eq!(note_ons("MML@{c{de}}4 c;"), vec![384, 432, 456, 480]);
+
+        // The Mabinogi dialect still floors c7 and ignores the second dot
+        let legacy = YksConverter::new("MML@c7c7c7c7c7c7c7c4..,,;".to_string(), 1);
+        let (_, report) = legacy.to_buffer_with_report().unwrap();
+        assert_eq!(report.warnings.len(), 1, "The second dot should be reported as ignored");
+    }
+
+    #[test]
+    fn should_report_malformed_tuplets() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let diagnostics = mml::parse_with("MML@{}4 {[c]}4 c} {c;", &Dialect::extended()).unwrap_err();
+        let found: Vec<_> = diagnostics.iter().map(|d| (d.code, d.text.as_str())).collect();
+        assert_eq!(found, vec![
+            (DiagnosticCode::MalformedTuplet, "{}4"),
+            (DiagnosticCode::MalformedTuplet, "["),
+            (DiagnosticCode::UnbalancedLoop, "]"),
+            (DiagnosticCode::MalformedTuplet, "}"),
+            (DiagnosticCode::MalformedTuplet, "{"),
+        ]);
+
+        let diagnostics = mml::parse("MML@{c}4c%30;").unwrap_err();
+        assert!(diagnostics.iter().all(|d| d.code == DiagnosticCode::UnknownCharacter), "Mabinogi has no tuplets");
+    }
+
+    #[test]
+    fn should_share_channels_past_sixteen_performers() {
+        let mut channels = ChannelAllocator::new();
+        let assigned: Vec<_> = (0..17).map(|i| channels.allocate(if i < 15 { i } else { 3 })).collect();
+        assert_eq!(assigned[..10], [1, 2, 3, 4, 5, 6, 7, 8, 9, 11].map(Some), "Channel 10 is kept for percussion");
+        assert_eq!(assigned[14], Some(16));
+        assert_eq!(assigned[15..], [Some(4), Some(4)], "Late performers share the channel of their instrument");
+        assert_eq!(channels.allocate(99), None);
+
+        // Twenty lutes fit on the first channel once the others are taken
+        let converter = YksConverter::new_multi(vec!["MML@c,d,e;".to_string(); 20], vec![1; 20]);
+        let buffer = converter.to_buffer_result().expect("Same-instrument performers should share channels");
+        let bytes = buffer.as_slice();
+        // Program change, delta of one tick, then the pan control change
+        let program_changes: Vec<u8> = bytes.windows(4)
+            .filter(|w| w[0] >> 4 == 0xc && w[1] == 0x01 && w[2] == 0x01 && w[3] >> 4 == 0xb)
+            .map(|w| w[0] & 0x0f)
+            .collect();
+        assert_eq!(program_changes.len(), 60);
+        assert!(!program_changes.contains(&9), "Channel 10 should stay free");
+        let text = constants::sysex::YOKOSO_META_TEXT.as_bytes();
+        assert_eq!(bytes.windows(text.len()).filter(|w| *w == text).count(), 1, "Only the first track opens the file");
+
+        let converter = YksConverter::new_multi(vec!["MML@c,,;".to_string(); 16], (0..16).collect());
+        assert_eq!(
+            converter.to_buffer_result().err(),
+            Some(ConversionError::ChannelsExhausted { index: 15, instrument: 15 })
+        );
+    }
+
+    #[test]
+    fn should_send_part_settings_before_the_first_note() {
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_settings(PartSettings { instrument: Some(200), pan: 20, reverb: 40, chorus: Some(30), volume: Some(200), ..Default::default() });
+        parser.set_part_settings(1, 2, PartSettings { instrument: Some(41), pan: 110, expression: Some(90), ..Default::default() });
+        parser.from_mml("MML@c,c,;").unwrap();
+
+        let setup = |track: &[Box<dyn crate::track_event::TrackEvent>]| -> Vec<String> {
+            track.iter()
+                .filter(|event| event.value().starts_with("ProgramChange") || event.value().starts_with("ControlChange"))
+                .map(|event| format!("{} {}", event.lead_time(), event.value()))
+                .collect()
+        };
+        let tracks = parser.build();
+        assert_eq!(setup(&tracks[0]), vec![
+            "192 ProgramChange: ch=1, program=127",
+            "193 ControlChange: ch=1, cc=10, val=20",
+            "194 ControlChange: ch=1, cc=91, val=40",
+            "195 ControlChange: ch=1, cc=93, val=30",
+            "197 ControlChange: ch=1, cc=7, val=127",
+        ]);
+        assert_eq!(setup(&tracks[1]), vec![
+            "192 ProgramChange: ch=2, program=41",
+            "193 ControlChange: ch=2, cc=10, val=110",
+            "194 ControlChange: ch=2, cc=91, val=0",
+            "196 ControlChange: ch=2, cc=11, val=90",
+        ]);
+        assert!(tracks[1].iter().any(|event| event.value().starts_with("NoteOn: ch=2")));
+        assert_eq!(setup(&tracks[2])[0], "192 ProgramChange: ch=1, program=127");
+
+        // A part with its own settings moves to the next free channel
+        let program_changes = |converter: &YksConverter| -> Vec<(u8, u8)> {
+            let buffer = converter.to_buffer_result().unwrap();
+            buffer.as_slice().windows(4)
+                .filter(|w| w[0] >> 4 == 0xc && w[2] == 0x01 && w[3] >> 4 == 0xb)
+                .map(|w| (w[0] & 0x0f, w[1]))
+                .collect()
+        };
+        let mut converter = YksConverter::new_multi(vec!["MML@c,d,e;".to_string(), "MML@c,,;".to_string()], vec![1, 7]);
+        converter.set_settings(1, PartSettings { pan: 0, ..Default::default() });
+        converter.set_part_settings(0, 2, PartSettings { instrument: Some(25), ..Default::default() });
+        converter.set_part_settings(0, 1, PartSettings::default());
+        assert_eq!(program_changes(&converter), vec![(0, 1), (0, 1), (1, 25), (2, 7), (2, 7), (2, 7)]);
+        assert_eq!(converter.settings(1).instrument, Some(7));
+    }
+
+    #[test]
+    fn should_look_up_instruments_by_name() {
+        use crate::mml::DiagnosticCode;
+
+        assert_eq!("lute".parse::<Instrument>(), Ok(Instrument::Lute));
+        assert_eq!("Electric Guitar".parse::<Instrument>(), Ok(Instrument::ElectricGuitar));
+        assert_eq!("electric_guitar".parse::<Instrument>(), Ok(Instrument::ElectricGuitar));
+        assert_eq!("drum kit".parse::<Instrument>(), Ok(Instrument::Drums));
+        assert_eq!("kazoo".parse::<Instrument>(), Err(ConversionError::UnknownInstrument("kazoo".to_string())));
+
+        for instrument in Instrument::ALL {
+            assert_eq!(instrument.name().parse::<Instrument>(), Ok(instrument));
+            assert!(instrument.program() < 128);
+            assert!(instrument.range().start() < instrument.range().end());
+            // The octave's c is a note the instrument plays
+            assert!(instrument.range().contains(&((instrument.default_octave() + 1) * 12)), "{}", instrument);
+        }
+        assert_eq!(Instrument::Tuba.default_octave(), 3);
+        assert_eq!(Instrument::Whistle.default_octave(), 6);
+        assert_eq!(Instrument::Lute.default_octave(), 4);
+        assert!(Instrument::Drums.is_percussion() && !Instrument::Piano.is_percussion());
+
+        let converter = YksConverter::new("MML@c,,;".to_string(), Instrument::Violin.into());
+        let buffer = converter.to_buffer_result().unwrap();
+        assert!(buffer.as_slice().windows(2).any(|w| w == [0xc0, 40]), "Violin should send GM program 40");
+
+        // A named instrument reports notes it cannot play
+        let mut converter = YksConverter::new("MML@o2c>>c,,;".to_string(), 1);
+        converter.set_instrument(0, Instrument::Violin);
+        let (buffer, report) = converter.to_buffer_with_report().unwrap();
+        assert!(buffer.as_slice().windows(2).any(|w| w == [0xc0, 40]));
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].diagnostic.code, DiagnosticCode::OutsideInstrumentRange);
+        assert_eq!(report.warnings[0].diagnostic.text, "c");
+        assert_eq!(report.warnings[0].diagnostic.offset, 6);
+    }
+
+    #[test]
+    fn should_play_drum_parts_on_the_percussion_channel() {
+        use crate::mml::Dialect;
+
+        let mut drums = DrumMap::default();
+        drums.set_pitch_class(11, 51); // b: ride cymbal
+        drums.set_note(72, 57);        // o5c: crash cymbal 2
+        let mut parser = Mf2tt2mf::new(10, 1, 64, 0);
+        parser.set_settings(PartSettings { percussion: Some(drums), ..Default::default() });
+        parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        parser.from_mml("MML@o2c o4d'cf+' b >c;").unwrap();
+
+        let track = &parser.build()[0];
+        assert!(!track.iter().any(|event| event.value().starts_with("ProgramChange")), "Drum kits need no program");
+        let note_ons: Vec<String> = track.iter()
+            .filter(|event| event.value().starts_with("NoteOn"))
+            .map(|event| event.value())
+            .collect();
+        assert_eq!(note_ons, vec![
+            "NoteOn: ch=10, note=36, vel=64",
+            "NoteOn: ch=10, note=38, vel=64",
+            "NoteOn: ch=10, note=36, vel=64",
+            "NoteOn: ch=10, note=42, vel=64",
+            "NoteOn: ch=10, note=51, vel=64",
+            "NoteOn: ch=10, note=57, vel=64",
+        ]);
+
+        // The drummer leaves channel 1 free for the next performer
+        let mut converter = YksConverter::new_multi(vec!["MML@c,,;".to_string(), "MML@c,,;".to_string()], vec![0, 24]);
+        converter.set_settings(0, PartSettings::percussion());
+        let buffer = converter.to_buffer_result().unwrap();
+        let bytes = buffer.as_slice();
+        assert!(bytes.windows(3).any(|w| w == [0x99, 36, 64]), "c should hit the bass drum on channel 10");
+        assert!(bytes.windows(2).any(|w| w == [0xc0, 24]));
+        assert!(!bytes.windows(2).any(|w| w[0] == 0xc9));
+    }
+
+    #[test]
+    fn should_play_the_drum_kit_on_the_percussion_channel() {
+        let mut converter = YksConverter::new("MML@cde,,;".to_string(), Instrument::Drums.into());
+        converter.set_instrument(0, Instrument::Drums);
+        let bytes = converter.to_buffer_result().unwrap().to_vec();
+        for drum in [36, 38, 40] {
+            assert!(bytes.windows(3).any(|w| w == [0x99, drum, 64]), "drum {} should sound on channel 10", drum);
+        }
+        assert!(!bytes.windows(2).any(|w| w[0] & 0xf0 == 0xc0), "the kit is chosen by the channel, not a program");
+
+        // Settings from the catalogue do the same for a single part
+        let mut converter = YksConverter::new("MML@c,c,;".to_string(), Instrument::Lute.into());
+        converter.set_part_settings(0, 1, PartSettings::from(Instrument::Drums));
+        let bytes = converter.to_buffer_result().unwrap().to_vec();
+        assert!(bytes.windows(3).any(|w| w == [0x90, 60, 64]));
+        assert!(bytes.windows(3).any(|w| w == [0x99, 36, 64]));
+    }
+
+    #[test]
+    fn should_scale_every_tick_to_the_timebase() {
+        use crate::mml::Dialect;
+
+        let times = |timebase: u16, mml: &str| -> Vec<(u32, String)> {
+            let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+            parser.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+            parser.set_timebase(timebase);
+            parser.from_mml(mml).unwrap();
+            parser.build().iter().flatten().map(|event| (event.lead_time(), event.value())).collect()
+        };
+
+        let mml = "MML@t150 c8.{ceg}4 c%30 q%12 d l3 e,,;";
+        let scaled: Vec<_> = times(96, mml).into_iter().map(|(time, value)| (time * 5, value)).collect();
+        assert_eq!(times(480, mml), scaled, "480 PPQN should be the 96 PPQN song with every time scaled by 5");
+
+        // Quintuplets round at 96 but land on whole ticks at 480
+        let note_ons = |timebase: u16| -> Vec<u32> {
+            times(timebase, "MML@{cdefg}4 c,,;").into_iter()
+                .filter(|(_, value)| value.starts_with("NoteOn"))
+                .map(|(time, _)| time)
+                .collect()
+        };
+        assert_eq!(note_ons(96), vec![384, 403, 422, 441, 460, 480]);
+        assert_eq!(note_ons(480), vec![1920, 2016, 2112, 2208, 2304, 2400]);
+
+        let mut converter = YksConverter::new("MML@c,,;".to_string(), 1);
+        converter.set_timebase(960);
+        let buffer = converter.to_buffer_result().unwrap();
+        assert_eq!(buffer.as_slice()[12..14], [0x03, 0xc0], "The header should carry the timebase");
+        converter.set_timebase(0);
+        assert_eq!(converter.to_buffer_result().err(), Some(ConversionError::InvalidTimebase(0)));
+    }
+
+    #[test]
+    fn should_drop_lead_in_and_branding_when_asked() {
+        let events = |options: ConversionOptions| -> Vec<String> {
+            let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+            parser.set_options(options);
+            parser.from_mml("MML@c,,;").unwrap();
+            parser.build()[0].iter().map(|event| format!("{} {}", event.lead_time(), event.value())).collect()
+        };
+
+        let options = ConversionOptions {
+            lead_in: 0,
+            gs_reset: false,
+            text: None,
+            copyright: Some("(c) 2026 Bard Guild".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(events(options), vec![
+            "0 Copyright: (c) 2026 Bard Guild",
+            "0 Tempo: 500000",
+            "0 ProgramChange: ch=1, program=1",
+            "0 ControlChange: ch=1, cc=10, val=64",
+            "0 ControlChange: ch=1, cc=91, val=0",
+            "0 NoteOn: ch=1, note=60, vel=64",
+            "96 NoteOff: ch=1, note=60, vel=0",
+            "192 EndOfTrack", // one default length after the last note, as always
+        ]);
+
+        // Half a bar keeps the program where it was and starts the notes at 192
+        let half_bar = events(ConversionOptions { lead_in: 192, ..Default::default() });
+        assert_eq!(half_bar[0], format!("0 Text: {}", constants::sysex::YOKOSO_META_TEXT));
+        assert_eq!(half_bar[3..6], ["192 ProgramChange: ch=1, program=1", "192 ControlChange: ch=1, cc=10, val=64", "192 ControlChange: ch=1, cc=91, val=0"]);
+        assert_eq!(half_bar[6], "192 NoteOn: ch=1, note=60, vel=64");
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { lead_in: 96, ..Default::default() });
+        parser.from_mml("MML@c,,;").unwrap();
+        assert_eq!(parser.build()[1].last().unwrap().lead_time(), 97, "Empty parts end a tick after the lead-in");
+    }
+
+    #[test]
+    fn should_write_markers_lyrics_and_track_names() {
+        use crate::mml::{DiagnosticCode, Dialect};
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions {
+            dialect: Dialect::extended(),
+            time_signature: Some((3, 4)),
+            key_signature: Some((-2, false)),
+            ..Default::default()
+        });
+        parser.set_track_name(0, "Lute".to_string());
+        parser.from_mml(r#"MML@!"Verse 1, A;" ~"la" c ~"li"d,e;"#).unwrap();
+
+        let tracks = parser.build();
+        let meta: Vec<String> = tracks[0].iter()
+            .filter(|event| !event.value().starts_with("Note") && !event.value().contains("Change"))
+            .map(|event| format!("{} {}", event.lead_time(), event.value()))
+            .collect();
+        assert_eq!(meta, vec![
+            "0 TrackName: Lute",
+            format!("0 Text: {}", constants::sysex::YOKOSO_META_TEXT).as_str(),
+            "0 Tempo: 500000",
+            "0 TimeSignature: 3/4",
+            "0 KeySignature: sharps=-2, major",
+            "0 SysEx: 41 10 42 12 40 00 7f 00 41 f7",
+            "384 Marker: Verse 1, A;",
+            "384 Lyric: la",
+            "480 Lyric: li",
+            "672 EndOfTrack",
+        ]);
+        assert_eq!(tracks.len(), 2, "Commas and semicolons inside quotes should not split parts");
+
+        let diagnostics = mml::parse_with(r#"MML@c~"la;"#, &Dialect::extended()).unwrap_err();
+        assert!(diagnostics.iter().any(|d| d.code == DiagnosticCode::UnterminatedText && d.text == r#""la;"#));
+        let diagnostics = mml::parse(r#"MML@!"A"c;"#).unwrap_err();
+        assert_eq!(diagnostics[0].code, DiagnosticCode::UnknownCharacter, "Mabinogi has no text commands");
+
+        // Tracks are named after the performer and part
+        let mut converter = YksConverter::new_multi(vec!["MML@c,d,;".to_string(), "MML@c,,;".to_string()], vec![1, 1]);
+        converter.set_name(0, "Alice".to_string());
+        converter.set_part_name(0, 1, "Chords".to_string());
+        let buffer = converter.to_buffer_result().unwrap();
+        let names: Vec<String> = buffer.as_slice().windows(3)
+            .enumerate()
+            .filter(|(_, w)| w[0] == 0xff && w[1] == 0x03)
+            .map(|(at, w)| String::from_utf8_lossy(&buffer.as_slice()[at + 3..at + 3 + w[2] as usize]).into_owned())
+            .collect();
+        assert_eq!(names, vec!["Alice - Part 1", "Alice - Chords", "Alice - Part 3"]);
+    }
+
+    #[test]
+    fn should_write_variable_length_quantities() {
+        use crate::track_event::{MetaText, SysEx, TrackEvent};
+
+        let encode = |value: u32| ByteBuffer::new().put_var_len(value).to_vec();
+        assert_eq!(encode(0), vec![0x00]);
+        assert_eq!(encode(0x7f), vec![0x7f]);
+        assert_eq!(encode(0x80), vec![0x81, 0x00]);
+        assert_eq!(encode(0x3fff), vec![0xff, 0x7f]);
+        assert_eq!(encode(0x4000), vec![0x81, 0x80, 0x00]);
+        assert_eq!(encode(0x0fff_ffff), vec![0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(encode(u32::MAX), vec![0x8f, 0xff, 0xff, 0xff, 0x7f]);
+
+        // 100 Hangul syllables are 300 UTF-8 bytes
+        let credits = "가".repeat(100);
+        let bytes = MetaText::new(credits.clone()).to_buffer().to_vec();
+        assert_eq!(bytes[..4], [0xff, 0x01, 0x82, 0x2c]);
+        assert_eq!(&bytes[4..], credits.as_bytes());
+
+        let bytes = SysEx::new(vec![0x7e; 200]).to_buffer().to_vec();
+        assert_eq!(bytes[..3], [0xf0, 0x81, 0x48]);
+        assert_eq!(bytes.len(), 203);
+    }
+
+    #[test]
+    fn should_merge_parts_into_one_track_or_split_off_a_conductor() {
+        use crate::conductor;
+        use crate::mf2tt2mf::header_events;
+
+        let mml = "MML@t150l4ce,l2<g,;";
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.from_mml(mml).unwrap();
+        let options = ConversionOptions::default();
+
+        // Format 1: the conductor holds the header and every tempo change
+        let tracks = conductor::with_conductor(header_events(&options), parser.build(), TempoPolicy::Latest);
+        assert_eq!(tracks.len(), 4);
+        let values: Vec<String> = tracks[0].iter().map(|event| event.value()).collect();
+        assert!(values.contains(&"Tempo: 400000".to_string()));
+        assert_eq!(values.last().unwrap(), "EndOfTrack");
+        assert!(tracks[0].iter().all(|event| event.status() >= 0xf0));
+        assert!(tracks[1..].iter().flatten().all(|event| event.meta_type() != Some(0x51)));
+        let last_part_end = tracks[1..].iter().map(|track| track.last().unwrap().lead_time()).max();
+        assert_eq!(Some(tracks[0].last().unwrap().lead_time()), last_part_end);
+
+        // Format 0: one time-ordered track with a single End of Track
+        let events = conductor::merged(header_events(&options), parser.build(), TempoPolicy::Latest);
+        assert!(events.windows(2).all(|pair| pair[0].lead_time() <= pair[1].lead_time()));
+        assert_eq!(events.iter().filter(|event| event.meta_type() == Some(0x2f)).count(), 1);
+        assert_eq!(events.iter().filter(|event| event.status() & 0xf0 == 0x90).count(), 3);
+
+        let mut converter = YksConverter::new(mml.to_string(), 1);
+        converter.set_options(ConversionOptions { format: OutputFormat::SingleTrack, ..Default::default() });
+        let buffer = converter.to_buffer_result().unwrap();
+        assert_eq!(buffer.as_slice()[8..12], [0x00, 0x00, 0x00, 0x01], "format 0, one track");
+        assert_eq!(buffer.as_slice().windows(4).filter(|w| w == b"MTrk").count(), 1);
+
+        converter.set_options(ConversionOptions { format: OutputFormat::Conductor, ..Default::default() });
+        let buffer = converter.to_buffer_result().unwrap();
+        assert_eq!(buffer.as_slice()[8..12], [0x00, 0x01, 0x00, 0x04], "format 1, conductor and three parts");
+    }
+
+    #[test]
+    fn should_resolve_tempo_changes_across_parts() {
+        use crate::conductor;
+        use crate::mf2tt2mf::header_events;
+
+        // Part 1 starts at 150 BPM, part 2 at 100 and switches to 200 a beat later
+        let options = ConversionOptions { lead_in: 0, ..Default::default() };
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(options.clone());
+        parser.from_mml("MML@t150l4cc,t100ct200c,;").unwrap();
+        let tempos = |policy| {
+            let tracks = conductor::with_conductor(header_events(&options), parser.build(), policy);
+            assert!(tracks[1..].iter().flatten().all(|event| event.meta_type() != Some(0x51)));
+            tracks[0].iter()
+                .filter(|event| event.meta_type() == Some(0x51))
+                .map(|event| (event.lead_time(), event.value()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tempos(TempoPolicy::Latest), vec![
+            (0, "Tempo: 600000".to_string()),
+            (96, "Tempo: 300000".to_string()),
+        ]);
+        assert_eq!(tempos(TempoPolicy::FirstPart), vec![(0, "Tempo: 400000".to_string())]);
+    }
+
+    #[test]
+    fn should_read_back_written_midi() {
+        use crate::midi::{self, ReadErrorKind};
+
+        let mml = "MML@t150l8cdr4e&e,l2<g,;";
+        let buffer = YksConverter::new(mml.to_string(), 1).to_buffer_result().unwrap();
+        let file = midi::read(buffer.as_slice()).unwrap();
+        assert_eq!((file.format, file.timebase), (1, 96));
+
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.from_mml(mml).unwrap();
+        let written: Vec<Vec<(u32, String)>> = parser.build().iter()
+            .map(|track| track.iter().map(|event| (event.lead_time(), event.value())).collect())
+            .collect();
+        let read: Vec<Vec<(u32, String)>> = file.tracks.iter()
+            .map(|track| track.iter().map(|event| (event.lead_time(), event.value())).collect())
+            .collect();
+        assert_eq!(read, written);
+
+        // Running status, pitch bend kept raw, and a truncated note
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk\x00\x00\x00\x0b".to_vec();
+        bytes.extend_from_slice(&[0x00, 0x90, 0x3c, 0x40, 0x60, 0x3c, 0x00, 0x00, 0xe0, 0x00, 0x40]);
+        let file = midi::read(&bytes).unwrap();
+        let values: Vec<String> = file.tracks[0].iter().map(|event| event.value()).collect();
+        assert_eq!(values, vec!["NoteOn: ch=1, note=60, vel=64", "NoteOn: ch=1, note=60, vel=0", "Raw: e0 00 40"]);
+        assert_eq!(file.tracks[0][1].lead_time(), 96);
+
+        let error = midi::read(&bytes[..bytes.len() - 2]).unwrap_err();
+        assert_eq!(error.kind, ReadErrorKind::UnexpectedEnd);
+        assert_eq!(error.offset, bytes.len() - 2);
+
+        bytes[23] = 0x3c;
+        let error = midi::read(&bytes).unwrap_err();
+        assert_eq!((error.kind, error.offset), (ReadErrorKind::MissingStatus, 23));
+        assert_eq!(midi::read(b"RIFF").unwrap_err().kind, ReadErrorKind::MissingHeader);
+    }
+
+    #[test]
+    fn should_convert_midi_back_to_mml() {
+        use crate::midi;
+
+        let notes = |mml: &[String]| {
+            let converter = YksConverter::new_multi(mml.to_vec(), vec![1; mml.len()]);
+            let buffer = converter.to_buffer_result().unwrap();
+            let file = midi::read(buffer.as_slice()).unwrap();
+            let mut notes: Vec<(u32, Vec<u8>)> = file.tracks.iter().flatten()
+                .filter(|event| matches!(event.status() & 0xf0, 0x80 | 0x90) || event.meta_type() == Some(0x51))
+                .map(|event| (event.lead_time(), event.to_buffer().to_vec()))
+                .collect();
+            notes.sort();
+            (buffer, notes)
+        };
+
+        // Ties, dots, odd divisions, octave jumps, volume and a tempo change
+        // in a later part that lands inside a first-part note
+        let original = vec![
+            "MML@t150l8cdr4e&e16.g>c<b o2c1&c4 v12a13a13a13,l2<g4.r8.t90>>>c+,;".to_string(),
+            "MML@l16ccccv3d2,,;".to_string(),
+        ];
+        let (buffer, expected) = notes(&original);
+        let mml = MidiToMml::new().convert(buffer.as_slice()).unwrap();
+        assert_eq!(mml.len(), 2);
+        let (_, converted) = notes(&mml);
+        assert_eq!(converted, expected, "{:?}", mml);
+
+        // The shortest spelling: a common `l`, and `<`/`>` only for small jumps
+        let (buffer, _) = notes(&["MML@l8cdefgab>c<<c>>>>>c,,;".to_string()]);
+        assert_eq!(MidiToMml::new().convert(buffer.as_slice()).unwrap(), vec!["MML@l8cdefgab>c<<co8c,,;"]);
+    }
+
+    #[test]
+    fn should_split_chords_into_monophonic_parts() {
+        use crate::mml::Dialect;
+        use crate::voices::{separate, Note};
+
+        let note = |start, end, pitch| Note { start, end, pitch, velocity: 64 };
+        let pitches = |lines: &[Vec<Note>]| -> Vec<Vec<u8>> {
+            lines.iter().map(|line| line.iter().map(|note| note.pitch).collect()).collect()
+        };
+        // A C major chord, then the top voice moves down while the bass holds
+        let notes = [note(0, 96, 60), note(0, 96, 64), note(0, 96, 67), note(0, 192, 48), note(96, 192, 65)];
+
+        let (lines, dropped) = separate(&notes, 3, VoiceStrategy::Highest);
+        assert_eq!(pitches(&lines), vec![vec![67, 65], vec![64], vec![60]]);
+        assert_eq!(dropped, vec![note(0, 192, 48)]);
+
+        let (lines, dropped) = separate(&notes, 3, VoiceStrategy::OuterVoices);
+        assert_eq!(pitches(&lines), vec![vec![67, 65], vec![48]]);
+        assert_eq!(dropped.len(), 2);
+        assert_eq!(lines[1][0].end, 192, "the bass is not cut by the melody");
+
+        // The F joins the E line it is nearest to, leaving the G ringing
+        let (lines, _) = separate(&notes[1..], 3, VoiceStrategy::Proximity);
+        assert_eq!(pitches(&lines), vec![vec![67], vec![64, 65], vec![48]]);
+        let (lines, _) = separate(&[note(0, 100, 60), note(96, 192, 62)], 3, VoiceStrategy::Proximity);
+        assert_eq!(pitches(&lines), vec![vec![60, 62]], "legato overlaps stay in one line");
+
+        // Chords written in one part come back as three parts
+        let mut converter = YksConverter::new("MML@l2'ceg''dfa>c<',,;".to_string(), 1);
+        converter.set_options(ConversionOptions { dialect: Dialect::extended(), ..Default::default() });
+        let buffer = converter.to_buffer_result().unwrap();
+        let (mml, report) = MidiToMml::new().convert_with_report(buffer.as_slice()).unwrap();
+        assert_eq!(mml, vec!["MML@g2>c2,e2a2,c2f2;"]);
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!((report.dropped[0].channel, report.dropped[0].note.pitch, report.dropped[0].note.start), (1, 62, 192));
+
+        let mut importer = MidiToMml::new();
+        importer.set_voices(1, VoiceStrategy::Highest);
+        assert_eq!(importer.convert(buffer.as_slice()).unwrap(), vec!["MML@g2>c2,,;"]);
+
+        // A held note cut short by a higher one is reported
+        let mut converter = YksConverter::new("MML@c1,r2>c2,;".to_string(), 1);
+        converter.set_options(ConversionOptions { format: OutputFormat::SingleTrack, ..Default::default() });
+        let buffer = converter.to_buffer_result().unwrap();
+        let (mml, report) = importer.convert_with_report(buffer.as_slice()).unwrap();
+        assert_eq!(mml, vec!["MML@c2>c2,,;"]);
+        assert_eq!(report.shortened.len(), 1);
+        assert_eq!((report.shortened[0].note.pitch, report.shortened[0].note.end, report.shortened[0].end), (60, 384, 192));
+        assert!(report.dropped.is_empty());
+    }
+
+    #[test]
+    fn should_minify_mml_without_changing_the_midi() {
+        let midi = |mml: &str| YksConverter::new(mml.to_string(), 1).to_buffer_result().unwrap().to_vec();
+        let mml = "MML@t120 O4 L8 C D E F G4 v8 A16 B16 >C4. <B A G F E D C2 r4, o3 c4 c4 c4 r4 r4 c&d&e l8, v15o6c8o2c8;";
+
+        let minified = Optimizer::new().minify(mml).unwrap();
+        assert_eq!(minified.mml, "MML@t120l8cdefg4a16b16>c4.<bagfedc2r4,<cccr2cdel8,v15o6c8o2c8;");
+        assert_eq!(midi(&minified.mml), midi(mml));
+        let lengths: Vec<(usize, usize)> = minified.parts.iter().map(|part| (part.before, part.after)).collect();
+        assert_eq!(lengths, vec![(56, 33), (26, 11), (11, 11)]);
+        assert!(minified.fits());
+
+        // Parts that stay too long after minifying are reported
+        let mut optimizer = Optimizer::new();
+        optimizer.set_limit(20);
+        let minified = optimizer.minify(mml).unwrap();
+        assert_eq!(minified.parts.iter().map(|part| part.fits()).collect::<Vec<_>>(), vec![false, true, true]);
+        assert!(!minified.fits());
+
+        assert!(Optimizer::new().minify("c d e").is_err());
+    }
+
+    #[test]
+    fn should_format_mml_without_changing_the_midi() {
+        let midi = |mml: &str| YksConverter::new(mml.to_string(), 1).to_buffer_result().unwrap().to_vec();
+        let mml = "MML@T120 L8 C#DEF G A B > C2  R4 C4 C1&C2 C2 L16 CDEFGAB,  o3 c2. c4 c4 c2. ,;";
+
+        let mut formatter = Formatter::new();
+        let formatted = formatter.format(mml).unwrap();
+        assert_eq!(formatted, "MML@t120l8c+defgab>c2r4c4c1&c2c2l16cdefgab,o3c2.c4c4c2.,;");
+        assert_eq!(midi(&formatted), midi(mml));
+        assert!(formatter.is_formatted(&formatted).unwrap());
+        assert!(!formatter.is_formatted(mml).unwrap());
+
+        // A space after the last command of each measure, or the note held
+        // across the bar line
+        formatter.set_bar_lines(Some((4, 4)));
+        let formatted = formatter.format(mml).unwrap();
+        assert_eq!(formatted, "MML@t120l8c+defgab>c2 r4c4c1& c2 c2l16cd efgab,o3c2.c4 c4c2.,;");
+        assert_eq!(midi(&formatted), midi(mml));
+        assert_eq!(formatter.format(&formatted).unwrap(), formatted);
+
+        formatter.set_bar_lines(Some((3, 4)));
+        assert_eq!(formatter.format("MML@c2.c4c4c4 c4c4c4,,;").unwrap(), "MML@c2. c4c4c4 c4c4c4,,;");
+
+        assert!(formatter.format("MML@c d e").is_err());
+    }
 }
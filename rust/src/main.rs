@@ -1,25 +1,108 @@
-use yks_converter::YksConverter;
+use yks_converter::{Formatter, Instrument, YksConverter};
+
+/// What to do with the MML given on the command line
+#[derive(PartialEq)]
+enum Mode {
+    Convert,
+    /// Print the MML in canonical form
+    Format,
+    /// Fail unless the MML is already in canonical form
+    Check,
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    
-    if args.len() > 1 {
-        let mml = &args[1];
-        let converter = YksConverter::new(mml.to_string(), 1);
-        
-        if let Some(buffer) = converter.to_buffer() {
-            std::fs::write("output.midi", buffer.as_slice()).unwrap();
-            println!("Generated output.midi ({} bytes)", buffer.size());
+    let mut args = std::env::args().skip(1);
+    let mut mml = None;
+    let mut inst = 1u8;
+    let mut instrument = None;
+    let mut mode = Mode::Convert;
+    let mut formatter = Formatter::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--inst" {
+            let Some(name) = args.next() else {
+                eprintln!("--inst needs an instrument name or program number");
+                std::process::exit(1);
+            };
+            inst = if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+                match name.parse::<u8>() {
+                    Ok(program) if program <= 127 => program,
+                    _ => {
+                        eprintln!("Program {} is out of range: GM program numbers go from 0 to 127", name);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                match name.parse::<Instrument>() {
+                    Ok(named) => {
+                        instrument = Some(named);
+                        named.into()
+                    }
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        let names: Vec<_> = Instrument::ALL.iter().map(|i| i.name()).collect();
+                        eprintln!("Instruments: {}", names.join(", "));
+                        std::process::exit(1);
+                    }
+                }
+            };
+        } else if arg == "--format" {
+            mode = Mode::Format;
+        } else if arg == "--check" {
+            mode = Mode::Check;
+        } else if arg == "--bars" {
+            formatter.set_bar_lines(Some((4, 4)));
         } else {
-            eprintln!("Failed to convert MML to MIDI");
+            mml = Some(arg);
+        }
+    }
+
+    if let Some(mml) = mml.as_ref().filter(|_| mode != Mode::Convert) {
+        let formatted = match formatter.format(mml) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}", diagnostic);
+                }
+                std::process::exit(1);
+            }
+        };
+        if mode == Mode::Format {
+            println!("{}", formatted);
+        } else if formatted != *mml {
+            eprintln!("MML is not formatted; expected:");
+            eprintln!("{}", formatted);
             std::process::exit(1);
         }
+    } else if let Some(mml) = mml {
+        let mut converter = YksConverter::new(mml, inst);
+        if let Some(instrument) = instrument {
+            converter.set_instrument(0, instrument);
+        }
+
+        match converter.to_buffer_with_report() {
+            Ok((buffer, report)) => {
+                for warning in &report.warnings {
+                    eprintln!("warning: {}", warning);
+                }
+                std::fs::write("output.midi", buffer.as_slice()).unwrap();
+                println!("Generated output.midi ({} bytes)", buffer.size());
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                eprintln!("Failed to convert MML to MIDI");
+                std::process::exit(1);
+            }
+        }
     } else {
         println!("YKS Converter - MML to MIDI converter");
-        println!("Usage: cargo run -- \"MML@t120l4cdefgab>c4.,,;\"");
+        println!("Usage: cargo run -- [--inst <instrument>] \"MML@t120l4cdefgab>c4.,,;\"");
+        println!("       cargo run -- --format|--check [--bars] \"MML@t120l4cdefgab>c4.,,;\"");
         println!();
         println!("Examples:");
         println!("  cargo run -- \"MML@t120l4cdefg,,;\"");
         println!("  cargo run -- \"MML@t190l8cdefgab>c4.,l8<cdefgab>c4.,l8>cdefgab>c4.;\"");
+        println!("  cargo run -- --inst lute \"MML@t120l4cdefg,,;\"");
+        println!("  cargo run -- --check --bars \"MML@t120l4cdef gab>c4.,,;\"");
     }
 }
@@ -1,26 +1,35 @@
 use crate::track_event::*;
 use crate::track_builder::TrackBuilder;
 use crate::constants::{mml, midi, sysex, control_change, event_timing, timing};
-use regex::Regex;
+use crate::mml::ast::{Accidental, Duration, NodeKind, OctaveShift, Score, Span, TextKind, Track};
+use crate::mml::diagnostic::{Diagnostic, DiagnosticCode};
+use crate::mml::parser;
+use crate::options::ConversionOptions;
+use crate::part_settings::PartSettings;
+use crate::percussion::DrumMap;
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
-// Keep original constants for compatibility (marked as used to avoid dead code warnings)
-#[allow(dead_code)]
 const MIN_VOLUME: u8 = mml::MIN_VOLUME;
-#[allow(dead_code)]
 const MAX_VOLUME: u8 = mml::MAX_VOLUME;
-#[allow(dead_code)]
 const MAX_OCTAVE: u8 = mml::MAX_OCTAVE;
 
 pub struct Mf2tt2mf {
     channel: u8,
     instrument: u8,
-    pan: u8,
-    reverb: u8,
+    settings: PartSettings,
+    part_settings: HashMap<usize, (u8, PartSettings)>,
     min_note: u8,
     max_note: u8,
     sound_map: HashMap<char, i32>,
     track_builders: Vec<TrackBuilder>,
+    warnings: Vec<Diagnostic>,
+    options: ConversionOptions,
+    writes_header: bool,
+    timebase: u16,
+    track_names: HashMap<usize, String>,
+    /// Tick each top-level node of each part starts at
+    node_starts: Vec<Vec<u32>>,
 }
 
 impl Mf2tt2mf {
@@ -44,312 +53,446 @@ impl Mf2tt2mf {
         Mf2tt2mf {
             channel,
             instrument,
-            pan,
-            reverb,
+            settings: PartSettings { pan, reverb, ..PartSettings::default() },
+            part_settings: HashMap::new(),
             min_note: 0,
             max_note: 96,
             sound_map,
             track_builders: Vec::new(),
+            warnings: Vec::new(),
+            options: ConversionOptions::default(),
+            writes_header: channel == 1,
+            timebase: timing::DEFAULT_TIMEBASE,
+            track_names: HashMap::new(),
+            node_starts: Vec::new(),
         }
     }
 
-    pub fn from_mml(&mut self, mml: &str) -> bool {
-        self.track_builders.clear();
-        
-        let regex_pattern = r"(MML@)\s*([\s0-9a-glnortvA-GLNORTV#<>.&+-]*),\s*([\s0-9a-glnortvA-GLNORTV#<>.&+-]*),\s*([\s0-9a-glnortvA-GLNORTV#<>.&+-]*);";
-        let regex = match Regex::new(regex_pattern) {
-            Ok(r) => r,
-            Err(_) => {
-                eprintln!("Regex compile failed");
-                return false;
-            }
-        };
+    pub fn set_options(&mut self, options: ConversionOptions) {
+        self.options = options;
+    }
 
-        let captures = match regex.captures(mml) {
-            Some(caps) => caps,
-            None => {
-                eprintln!("Regex parse failed");
-                return false;
-            }
-        };
+    /// Settings for every part without its own
+    ///
+    /// Replaces the pan and reverb given to [`new`](Self::new); an
+    /// instrument of `None` keeps the one given there.
+    pub fn set_settings(&mut self, settings: PartSettings) {
+        self.settings = settings;
+    }
 
-        let mut track_list = Vec::new();
-        
-        for i in 1..captures.len() {
-            if let Some(track_match) = captures.get(i) {
-                let track = track_match.as_str();
-                if !track.starts_with("MML@") {
-                    track_list.push(track.to_string());
-                }
-            }
-        }
+    /// Channel and settings for one part, replacing the shared ones
+    pub fn set_part_settings(&mut self, part: usize, channel: u8, settings: PartSettings) {
+        self.part_settings.insert(part, (channel, settings));
+    }
 
-        if track_list.is_empty() {
-            eprintln!("Track is empty");
-            return false;
-        }
+    /// Ticks per quarter note; every length and offset scales with it
+    pub fn set_timebase(&mut self, timebase: u16) {
+        self.timebase = timebase;
+    }
+
+    /// Names the track of `part` with a track name meta event (FF 03)
+    pub fn set_track_name(&mut self, part: usize, name: String) {
+        self.track_names.insert(part, name);
+    }
+
+    /// Whether the first track starts with the text, tempo and SysEx events
+    /// that open the file. Defaults to `true` on channel 1 only.
+    pub fn set_writes_header(&mut self, writes_header: bool) {
+        self.writes_header = writes_header;
+    }
+
+    /// Parses `mml` and builds one MIDI track per part
+    ///
+    /// On failure nothing is built and every problem found in the string is
+    /// returned with its position. Tokens that were clamped, ignored or
+    /// coerced are listed in [`warnings`](Self::warnings) afterwards.
+    pub fn from_mml(&mut self, mml: &str) -> Result<(), Vec<Diagnostic>> {
+        self.track_builders.clear();
+        self.warnings.clear();
+
+        let score = parser::parse_with(mml, &self.options.dialect)?;
+        self.from_score(mml, &score);
+        Ok(())
+    }
 
-        let ch = self.channel;
-        let inst = self.instrument;
-        let pan = self.pan;
-        let reverb = self.reverb;
+    /// Builds one MIDI track per part of `score`, parsed from `mml`
+    pub fn from_score(&mut self, mml: &str, score: &Score) {
+        self.track_builders.clear();
+        self.warnings.clear();
+        self.node_starts.clear();
+        let mut warnings = Vec::new();
 
-        for (i, track) in track_list.iter().enumerate() {
+        for (i, track) in score.tracks.iter().enumerate() {
+            let (ch, settings) = match self.part_settings.get(&i) {
+                Some((channel, settings)) => (*channel, settings.or_instrument(self.instrument)),
+                None => (self.channel, self.settings.or_instrument(self.instrument)),
+            };
             let mut builder = TrackBuilder::new(ch);
-            
-            if ch == 1 && i == 0 {
-                let meta_text = Box::new(MetaText::new(sysex::YOKOSO_META_TEXT.to_string()));
-                let tempo = Box::new(Tempo::new(midi::DEFAULT_TEMPO_MICROSECONDS));
-                let sys_ex = Box::new(SysEx::new(sysex::YOKOSO_SYSEX_DATA.to_vec()));
-                
-                builder.put_event(meta_text);
-                builder.put_event(tempo);
-                builder.put_event(sys_ex);
-            }
 
-            let mut prog_change = Box::new(ProgramChange::new(ch, inst));
-            prog_change.set_lead_time(event_timing::PROGRAM_CHANGE_OFFSET);
-            builder.put_event(prog_change);
+            if let Some(name) = self.track_names.get(&i) {
+                builder.put_event(Box::new(TrackName::new(name.clone())));
+            }
+            if self.writes_header && i == 0 {
+                builder.put_events(header_events(&self.options));
+            }
 
-            let mut pan_control = Box::new(ControlChange::new(ch, control_change::PAN, pan));
-            pan_control.set_lead_time(event_timing::PAN_CONTROL_OFFSET);
-            builder.put_event(pan_control);
+            // Drum kits are picked by the channel, not a program
+            if settings.percussion.is_none() {
+                let mut prog_change = Box::new(ProgramChange::new(ch, settings.instrument.unwrap_or(self.instrument).min(127)));
+                prog_change.set_lead_time(self.setup_time(event_timing::PROGRAM_CHANGE_OFFSET));
+                builder.put_event(prog_change);
+            }
 
-            let mut reverb_control = Box::new(ControlChange::new(ch, control_change::REVERB, reverb));
-            reverb_control.set_lead_time(event_timing::REVERB_CONTROL_OFFSET);
-            builder.put_event(reverb_control);
+            let controls = [
+                (control_change::PAN, Some(settings.pan), event_timing::PAN_CONTROL_OFFSET),
+                (control_change::REVERB, Some(settings.reverb), event_timing::REVERB_CONTROL_OFFSET),
+                (control_change::CHORUS, settings.chorus, event_timing::CHORUS_CONTROL_OFFSET),
+                (control_change::EXPRESSION, settings.expression, event_timing::EXPRESSION_CONTROL_OFFSET),
+                (control_change::MAIN_VOLUME, settings.volume, event_timing::VOLUME_CONTROL_OFFSET),
+            ];
+            for (controller, value, time) in controls {
+                if let Some(value) = value {
+                    let mut control = Box::new(ControlChange::new(ch, controller, value.min(127)));
+                    control.set_lead_time(self.setup_time(time));
+                    builder.put_event(control);
+                }
+            }
 
             if !track.is_empty() {
-                let track_events = self.parse_track(track, event_timing::TRACK_START_TIME);
+                let output = PartOutput { channel: ch, drums: settings.percussion.as_ref(), range: settings.range.as_ref() };
+                let (track_events, starts) = self.parse_track(mml, i, output, track, self.scaled(self.options.lead_in), &mut warnings);
                 builder.put_events(track_events);
+                self.node_starts.push(starts);
             } else {
                 let mut end_track = Box::new(EndOfTrack::new());
-                end_track.set_lead_time(event_timing::EMPTY_TRACK_END_TIME);
+                let end = event_timing::EMPTY_TRACK_END_TIME - event_timing::TRACK_START_TIME;
+                end_track.set_lead_time(self.scaled(self.options.lead_in.saturating_add(end)));
                 builder.put_event(end_track);
+                self.node_starts.push(Vec::new());
             }
 
             self.track_builders.push(builder);
         }
 
-        true
+        self.warnings = warnings;
     }
 
-    fn parse_track(&self, track: &str, lead_time: u32) -> Vec<Box<dyn TrackEvent>> {
-        use regex::Regex;
-        
+    /// Tick each top-level node of `part` starts at in the last build, a
+    /// loop at its first pass
+    pub(crate) fn node_starts(&self, part: usize) -> &[u32] {
+        self.node_starts.get(part).map_or(&[], Vec::as_slice)
+    }
+
+    /// Tokens from the last `from_mml` call that did not play as written
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn parse_track(
+        &self,
+        source: &str,
+        index: usize,
+        output: PartOutput,
+        track: &Track,
+        lead_time: u32,
+        warnings: &mut Vec<Diagnostic>,
+    ) -> (Vec<Box<dyn TrackEvent>>, Vec<u32>) {
+        let warn = |code: DiagnosticCode, span: Span, message: String| {
+            Diagnostic::new(code, Some(index), span.start, span.text(source), message)
+        };
+        let PartOutput { channel, drums, range } = output;
+        let first_warning = warnings.len();
+        for &span in &track.skipped {
+            warnings.push(warn(DiagnosticCode::IgnoredText, span, format!("'{}' was ignored", span.text(source))));
+        }
+
         let mut events: Vec<Box<dyn TrackEvent>> = Vec::new();
         let mut delta_time = lead_time;
         
         // C++ algorithm state variables using constants
-        let mut note_time = timing::TICKS_PER_QUARTER_NOTE; // Current note duration (quarter note = 96 ticks)
+        let mut note_time = self.exact(timing::TICKS_PER_QUARTER_NOTE); // Current note duration (quarter note = 96 ticks)
         let mut octave = mml::DEFAULT_OCTAVE;               // Current octave
         let mut volume = mml::DEFAULT_VOLUME;               // Current volume (1-15)
-        let mut curr_note = 0i32;                           // For tie processing
+        let mut curr_notes = vec![0i32];                    // Pitches held by a tie
         let mut is_tied = false;                            // Tie state
+        let mut gate = Gate::Eighths(mml::GATE_STEPS);      // `q` gate time
+        let mut transpose = 0i32;                           // `k`/`_` semitones
+        let mut carry = ExactTicks::whole(0);               // Fraction of a tick not yet played
+        let mut tuplets = Tuplets::default();               // Open `{}` groups
         
-        // C++ time constants using defined constants
-        let semibreve = timing::TICKS_PER_WHOLE_NOTE; // Whole note = 384 ticks
-        let minim = timing::TICKS_PER_HALF_NOTE;      // Half note = 192 ticks
-        
-        // Step 1: Remove whitespace (C++ line 115-116)
-        let clean_track = track.chars().filter(|c| !c.is_whitespace()).collect::<String>();
-        
-        // Step 2: Extract all MML tokens using exact C++ regex pattern (line 118)
-        let token_regex = Regex::new(r"[OTLVNRA-Gotlvnra-g<>][\+\-\#]?[0-9]*\.?&?").unwrap();
-        let tokens: Vec<&str> = token_regex.find_iter(&clean_track).map(|m| m.as_str()).collect();
-        
-        // Step 3: Process tokens in order (C++ lines 130-259)
-        for token in tokens {
-            // Parse control tokens first (length, octave, tempo, volume, octave shift)
-            let control_regex = Regex::new(r"([lotvLOTV<>])([1-9][0-9]*|0?)(\.?)(&?)").unwrap();
-            if let Some(caps) = control_regex.captures(token) {
-                let op = caps.get(1).unwrap().as_str().to_lowercase();
-                let value_str = caps.get(2).map_or("", |m| m.as_str());
-                let dot = caps.get(3).map_or("", |m| m.as_str());
-                let _ampersand = caps.get(4).map_or("", |m| m.as_str()); // For ties
-                
-                let value = if value_str.is_empty() { 0i32 } else { value_str.parse::<i32>().unwrap_or(0) };
-                
-                match op.as_str() {
-                    "l" => {
-                        // Length token (C++ lines 142-147)
-                        if value > 0 && value <= minim as i32 {
-                            note_time = semibreve / value as u32; // C++ formula: floor(semibreve/value)
-                            if dot == "." {
-                                note_time = (note_time as f32 * 1.5) as u32; // Dotted notes
-                            }
+        // Whitespace removal and tokenizing (C++ lines 115-118) happen in the
+        // lexer; the nodes arrive with values already read the C++ way.
+        // Process nodes in order (C++ lines 130-259), with loops unrolled
+        let mut starts = Vec::with_capacity(track.nodes.len());
+        for node in track.expanded() {
+            // Loops replay earlier nodes; each top-level node is timed once,
+            // when playing first reaches it
+            while track.nodes.get(starts.len()).is_some_and(|top| top.span.start <= node.span.start) {
+                starts.push(delta_time);
+            }
+
+            // Control nodes update state and move on; notes and chords yield
+            // (pitches, tick, tie)
+            let (pitches, duration, tie) = match node.kind {
+                NodeKind::Length { value, dots, tied } => {
+                    // Length token (C++ lines 142-147)
+                    match value.map(|value| (value, self.base_length(value))) {
+                        None => warnings.push(warn(DiagnosticCode::LengthIgnored, node.span,
+                            "'l' without a length was ignored".to_string())),
+                        Some((value, None)) => warnings.push(warn(DiagnosticCode::LengthIgnored, node.span,
+                            length_message(value, "was ignored"))),
+                        Some((_, Some(base))) => {
+                            note_time = self.dotted(base, dots);
                             // Handle ties (&) - for now just mark the flag
-                            if _ampersand == "&" {
+                            if tied {
                                 is_tied = true;
                             }
                         }
                     }
-                    "o" => {
-                        // Octave token (C++ lines 148-149)
-                        octave = value;
+                    continue;
+                }
+                NodeKind::Octave(value) => {
+                    // Octave token (C++ lines 148-149)
+                    octave = value.unwrap_or(0) as i32;
+                    if octave > MAX_OCTAVE as i32 {
+                        warnings.push(warn(DiagnosticCode::OctaveOutOfRange, node.span,
+                            format!("octave {} is above {}; notes will wrap down", octave, MAX_OCTAVE)));
                     }
-                    "t" => {
-                        // Tempo token (C++ lines 164-167)
-                        if value > 0 {
-                            let tempo_microseconds = (60_000_000 / value) as u32; // Use integer division like C++
-                            let mut tempo_event: Box<dyn TrackEvent> = Box::new(Tempo::new(tempo_microseconds));
-                            tempo_event.set_lead_time(delta_time);
-                            events.push(tempo_event);
-                        }
+                    continue;
+                }
+                NodeKind::Tempo(value) => {
+                    // Tempo token (C++ lines 164-167), integer division like C++
+                    if let Some(tempo_microseconds) = 60_000_000u32.checked_div(value.unwrap_or(0)) {
+                        let mut tempo_event: Box<dyn TrackEvent> = Box::new(Tempo::new(tempo_microseconds));
+                        tempo_event.set_lead_time(delta_time);
+                        events.push(tempo_event);
+                    } else {
+                        let message = if value.is_some() { "tempo 0 was ignored" } else { "'t' without a tempo was ignored" };
+                        warnings.push(warn(DiagnosticCode::TempoIgnored, node.span, message.to_string()));
                     }
-                    "v" => {
-                        // Volume token (C++ lines 168-174)
-                        if value < 1 {
-                            volume = 1;
-                        } else if value > 15 {
-                            volume = 15;
-                        } else {
-                            volume = value;
-                        }
+                    continue;
+                }
+                NodeKind::Text { kind, ref text } => {
+                    let mut text_event: Box<dyn TrackEvent> = match kind {
+                        TextKind::Marker => Box::new(Marker::new(text.clone())),
+                        TextKind::Lyric => Box::new(Lyric::new(text.clone())),
+                    };
+                    text_event.set_lead_time(delta_time);
+                    events.push(text_event);
+                    continue;
+                }
+                NodeKind::Gate { value, ticks } => {
+                    match (value, ticks) {
+                        (Some(value), true) => gate = Gate::Cut(self.exact(value).floor()),
+                        (Some(value), false) if (1..=mml::GATE_STEPS).contains(&value) => gate = Gate::Eighths(value),
+                        (Some(value), false) => warnings.push(warn(DiagnosticCode::GateIgnored, node.span,
+                            format!("gate time q{} is outside 1-{} and was ignored", value, mml::GATE_STEPS))),
+                        (None, _) => warnings.push(warn(DiagnosticCode::GateIgnored, node.span,
+                            "'q' without a value was ignored".to_string())),
                     }
-                    "<" => {
-                        // Octave down (C++ lines 175-180)
-                        if octave <= 0 {
-                            octave = 0;
-                        } else {
-                            octave -= 1;
-                        }
+                    continue;
+                }
+                NodeKind::Transpose { semitones: Some(semitones), relative } => {
+                    let requested = if relative { transpose.saturating_add(semitones) } else { semitones };
+                    transpose = requested.clamp(-mml::MAX_TRANSPOSE, mml::MAX_TRANSPOSE);
+                    if transpose != requested {
+                        warnings.push(warn(DiagnosticCode::TransposeClamped, node.span,
+                            format!("transpose {} was clamped to {}", requested, transpose)));
                     }
-                    ">" => {
-                        // Octave up (C++ lines 181-186)  
-                        if octave >= 9 {
-                            octave = 9;
-                        } else {
-                            octave += 1;
-                        }
+                    continue;
+                }
+                NodeKind::Transpose { semitones: None, .. } => {
+                    warnings.push(warn(DiagnosticCode::TransposeIgnored, node.span,
+                        format!("'{}' without a value was ignored", node.span.text(source))));
+                    continue;
+                }
+                NodeKind::Volume(value) => {
+                    // Volume token (C++ lines 168-174)
+                    let value = value.unwrap_or(0) as i32;
+                    volume = value.clamp(MIN_VOLUME as i32, MAX_VOLUME as i32);
+                    if volume != value {
+                        warnings.push(warn(DiagnosticCode::VolumeClamped, node.span,
+                            format!("volume {} was clamped to {}", value, volume)));
                     }
-                    _ => {}
+                    continue;
                 }
-            } else {
-                // Parse note/rest tokens (C++ lines 188-257)
-                let note_regex = Regex::new(r"([a-gnA-GN])([\+\#-]?)([0-9]*)(\.?)(&?)").unwrap();
-                if let Some(caps) = note_regex.captures(token) {
-                    let note_char = caps.get(1).unwrap().as_str().to_lowercase();
-                    let pitch = caps.get(2).map_or("", |m| m.as_str());
-                    let length_str = caps.get(3).map_or("", |m| m.as_str());
-                    let dot = caps.get(4).map_or("", |m| m.as_str());
-                    let ampersand = caps.get(5).map_or("", |m| m.as_str());
-                    
-                    let mut note = 0i32;
-                    let mut tick = note_time;
-                    
+                NodeKind::OctaveShift(shift) => {
+                    // Octave down/up (C++ lines 175-186)
+                    if let Some(message) = shift_octave(&mut octave, shift) {
+                        warnings.push(warn(DiagnosticCode::OctaveShiftClamped, node.span, message));
+                    }
+                    continue;
+                }
+                NodeKind::Rest { length, dots } => {
+                    // Handle rest tokens 'r' (C++ lines 246-257)
+                    let (duration, ignored) = self.note_ticks(&mut tuplets, length, dots, note_time);
+                    if let Some(message) = ignored {
+                        warnings.push(warn(DiagnosticCode::LengthIgnored, node.span, message));
+                    }
+                    delta_time = delta_time.saturating_add(carry.advance(duration));
+                    continue;
+                }
+                NodeKind::Tuplet { ref body, length, dots } => {
+                    // Members that follow share this length equally
+                    let (duration, ignored) = self.note_ticks(&mut tuplets, length, dots, note_time);
+                    if let Some(message) = ignored {
+                        warnings.push(warn(DiagnosticCode::LengthIgnored, node.span, message));
+                    }
+                    tuplets.open(duration, body.iter().filter(|member| member.kind.takes_time()).count());
+                    continue;
+                }
+                NodeKind::AbsoluteNote { value, tied } => {
                     // Handle 'n' notes differently (C++ lines 196-200)
-                    if note_char == "n" {
-                        if !length_str.is_empty() {
-                            if let Ok(value) = length_str.parse::<i32>() {
-                                if value >= 0 && value <= self.max_note as i32 {
-                                    note = value;
+                    let (note, out_of_range) = self.absolute_note(value);
+                    if let Some(message) = out_of_range {
+                        warnings.push(warn(DiagnosticCode::NoteNumberOutOfRange, node.span, message));
+                    }
+                    let (duration, _) = self.note_ticks(&mut tuplets, None, 0, note_time);
+                    (vec![(note, node.span)], duration, tied)
+                }
+                NodeKind::Note { note: name, accidental, length, dots, tied } => {
+                    // Regular note processing (C++ lines 201-216)
+                    let (duration, ignored) = self.note_ticks(&mut tuplets, length, dots, note_time);
+                    if let Some(message) = ignored {
+                        warnings.push(warn(DiagnosticCode::LengthIgnored, node.span, message));
+                    }
+                    (vec![(self.note_number(name, accidental, octave), node.span)], duration, tied)
+                }
+                NodeKind::Chord { ref notes, length, dots, tied } => {
+                    // Every pitch starts together and lasts the chord length
+                    let (duration, ignored) = self.note_ticks(&mut tuplets, length, dots, note_time);
+                    if let Some(message) = ignored {
+                        warnings.push(warn(DiagnosticCode::LengthIgnored, node.span, message));
+                    }
+                    let mut pitches = Vec::with_capacity(notes.len());
+                    for inner in notes {
+                        match inner.kind {
+                            NodeKind::Note { note: name, accidental, length, dots, .. } => {
+                                if length.is_some() || dots > 0 {
+                                    warnings.push(warn(DiagnosticCode::LengthIgnored, inner.span,
+                                        "length inside a chord was ignored; the chord length is used".to_string()));
                                 }
+                                pitches.push((self.note_number(name, accidental, octave), inner.span));
                             }
-                        }
-                    } else {
-                        // Regular note processing (C++ lines 201-216)
-                        if !length_str.is_empty() {
-                            if let Ok(length_val) = length_str.parse::<i32>() {
-                                if length_val >= 1 && length_val <= minim as i32 {
-                                    tick = semibreve / length_val as u32;
+                            NodeKind::AbsoluteNote { value, .. } => {
+                                let (note, out_of_range) = self.absolute_note(value);
+                                if let Some(message) = out_of_range {
+                                    warnings.push(warn(DiagnosticCode::NoteNumberOutOfRange, inner.span, message));
                                 }
+                                pitches.push((note, inner.span));
                             }
-                        }
-                        if dot == "." {
-                            tick = (tick as f32 * 1.5) as u32;
-                        }
-                        
-                        // Calculate MIDI note number from sound map (C++ lines 208-210)
-                        if let Some(&base_note) = self.sound_map.get(&note_char.chars().next().unwrap()) {
-                            note = (12 * octave) + base_note;
-                        }
-                        
-                        // Apply accidentals (C++ lines 211-215)
-                        if pitch == "+" || pitch == "#" {
-                            note += 1;
-                        } else if pitch == "-" {
-                            note -= 1;
-                        }
-                    }
-                    
-                    // Clamp to valid range (C++ lines 218-224)
-                    while note < self.min_note as i32 { note += 12; }
-                    while note > self.max_note as i32 { note -= 12; }
-                    note += 12; // Final offset (C++ line 224)
-                    
-                    // Handle ties and note events (C++ lines 226-241)
-                    if is_tied && note != curr_note {
-                        is_tied = false;
-                        // Generate Note Off for previous tied note
-                        let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(self.channel, curr_note as u8, 0));
-                        note_off.set_lead_time(delta_time);
-                        events.push(note_off);
-                    }
-                    
-                    if !is_tied {
-                        // Generate Note On (C++ line 232)
-                        let note_number = note as u8;
-                        let velocity = (mml::VELOCITY_MULTIPLIER * volume) as u8;
-                        let mut note_on: Box<dyn TrackEvent> = Box::new(NoteOn::new(self.channel, note_number, velocity));
-                        note_on.set_lead_time(delta_time);
-                        events.push(note_on);
-                    }
-                    
-                    delta_time += tick; // Advance time (C++ line 234)
-                    
-                    if ampersand == "&" {
-                        is_tied = true;
-                        curr_note = note;
-                    } else {
-                        is_tied = false;
-                        // Generate Note Off (C++ line 240)
-                        let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(self.channel, note as u8, 0));
-                        note_off.set_lead_time(delta_time);
-                        events.push(note_off);
-                    }
-                } else {
-                    // Handle rest tokens 'r' (C++ lines 246-257)
-                    let rest_regex = Regex::new(r"[rR]([0-9]*)(\.?)").unwrap();
-                    if let Some(caps) = rest_regex.captures(token) {
-                        let mut tick = note_time;
-                        let length_str = caps.get(1).map_or("", |m| m.as_str());
-                        let dot = caps.get(2).map_or("", |m| m.as_str());
-                        
-                        if let Ok(length_val) = length_str.parse::<i32>() {
-                            if length_val >= 1 && length_val <= minim as i32 {
-                                tick = semibreve / length_val as u32;
+                            NodeKind::OctaveShift(shift) => {
+                                if let Some(message) = shift_octave(&mut octave, shift) {
+                                    warnings.push(warn(DiagnosticCode::OctaveShiftClamped, inner.span, message));
+                                }
                             }
+                            // The parser admits nothing else inside a chord
+                            _ => {}
                         }
-                        if dot == "." {
-                            tick = (tick as f32 * 1.5) as u32;
-                        }
-                        delta_time += tick;
                     }
+                    (pitches, duration, tied)
+                }
+                // `expanded()` has already unrolled these
+                NodeKind::Loop { .. } | NodeKind::LoopBreak => continue,
+            };
+
+            let tick = carry.advance(duration);
+
+            // Clamp to valid range (C++ lines 218-224)
+            let mut notes: Vec<i32> = Vec::with_capacity(pitches.len());
+            for (note, span) in pitches {
+                let mut note = note + transpose + self.options.transpose.clamp(-mml::MAX_TRANSPOSE, mml::MAX_TRANSPOSE);
+                let written = note;
+                while note < self.min_note as i32 { note += 12; }
+                while note > self.max_note as i32 { note -= 12; }
+                if note != written {
+                    let octaves = (note - written) / 12;
+                    warnings.push(warn(DiagnosticCode::NoteWrapped, span, format!(
+                        "pitch moved {} {} octave{} into range",
+                        if octaves > 0 { "up" } else { "down" },
+                        octaves.abs(),
+                        if octaves.abs() == 1 { "" } else { "s" },
+                    )));
+                }
+                note += 12; // Final offset (C++ line 224)
+                if let Some(range) = range.filter(|range| !range.contains(&(note as u8))) {
+                    warnings.push(warn(DiagnosticCode::OutsideInstrumentRange, span, format!(
+                        "note {} is outside the instrument's range {}-{}", note, range.start(), range.end())));
+                }
+                if let Some(drums) = drums {
+                    note = drums.drum(note as u8) as i32;
+                }
+                if !notes.contains(&note) {
+                    notes.push(note);
+                }
+            }
+
+            // Handle ties and note events (C++ lines 226-241). Pitches held by
+            // a tie carry on; the rest of the held chord is released first.
+            let held: &[i32] = if is_tied { &curr_notes } else { &[] };
+            for &held_note in held.iter().filter(|held_note| !notes.contains(held_note)) {
+                // Generate Note Off for previous tied note
+                let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(channel, held_note as u8, 0));
+                note_off.set_lead_time(delta_time);
+                events.push(note_off);
+            }
+
+            for &note in notes.iter().filter(|note| !held.contains(note)) {
+                // Generate Note On (C++ line 232)
+                let velocity = (mml::VELOCITY_MULTIPLIER * volume) as u8;
+                let mut note_on: Box<dyn TrackEvent> = Box::new(NoteOn::new(channel, note as u8, velocity));
+                note_on.set_lead_time(delta_time);
+                events.push(note_on);
+            }
+
+            // Tied notes sound to the end; the gate only shortens the release
+            // Saturating, since long songs at a large timebase can pass u32
+            let release = delta_time.saturating_add(gate.sounding(tick));
+            delta_time = delta_time.saturating_add(tick); // Advance time (C++ line 234)
+
+            if tie {
+                is_tied = true;
+                curr_notes = notes;
+            } else {
+                is_tied = false;
+                // Generate Note Off (C++ line 240)
+                for &note in &notes {
+                    let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(channel, note as u8, 0));
+                    note_off.set_lead_time(release);
+                    events.push(note_off);
                 }
             }
         }
-        
+
         // Handle remaining tied notes (C++ lines 242-245)
         if is_tied {
-            let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(self.channel, curr_note as u8, 0));
-            note_off.set_lead_time(delta_time);
-            events.push(note_off);
+            for &note in &curr_notes {
+                let mut note_off: Box<dyn TrackEvent> = Box::new(NoteOff::new(channel, note as u8, 0));
+                note_off.set_lead_time(delta_time);
+                events.push(note_off);
+            }
         }
         
+        starts.resize(track.nodes.len(), delta_time);
+
         // Add final note time like C++ (line 260)
-        delta_time += note_time;
+        delta_time = delta_time.saturating_add(carry.advance(note_time));
         
         let mut end_track: Box<dyn TrackEvent> = Box::new(EndOfTrack::new());
         end_track.set_lead_time(delta_time);
         events.push(end_track);
-        
-        events
+
+        // Stable sort, then one warning per token even when a loop replays it
+        let mut track_warnings = warnings.split_off(first_warning);
+        track_warnings.sort_by_key(|warning| warning.offset);
+        track_warnings.dedup_by(|a, b| a.offset == b.offset && a.code == b.code);
+        warnings.extend(track_warnings);
+        (events, starts)
     }
 
     pub fn build_to_string(&self) -> Vec<String> {
         let mut result = Vec::new();
-        result.push(format!("MFile 1 {} 96", self.channel));
+        result.push(format!("MFile 1 {} {}", self.channel, self.timebase));
         
         for builder in &self.track_builders {
             let build_result = builder.build();
@@ -374,3 +517,253 @@ impl Mf2tt2mf {
         result
     }
 }
+
+impl Mf2tt2mf {
+    /// Length of a note, rest, chord or tuplet, falling back to `default`
+    /// when the written length is out of range. Inside a tuplet the written
+    /// length gives way to an equal share of the tuplet.
+    fn note_ticks(&self, tuplets: &mut Tuplets, length: Option<Duration>, dots: u8, default: ExactTicks) -> (ExactTicks, Option<String>) {
+        if let Some(share) = tuplets.take_share() {
+            let ignored = (length.is_some() || dots > 0)
+                .then(|| "length inside a tuplet was ignored; members share the tuplet length equally".to_string());
+            return (share, ignored);
+        }
+        let (base, ignored) = match length.map(|length| (length, self.base_length(length))) {
+            Some((_, Some(base))) => (base, None),
+            Some((length, None)) => (default, Some(length_message(length, "was ignored; the default length is used"))),
+            None => (default, None),
+        };
+        (self.dotted(base, dots), ignored)
+    }
+
+    /// Ticks for `4` or `%30`, or `None` when out of range (1-192, %1-%768). Divisions are
+    /// floored to whole ticks unless the dialect has exact lengths.
+    fn base_length(&self, length: Duration) -> Option<ExactTicks> {
+        let semibreve = self.exact(timing::TICKS_PER_WHOLE_NOTE).floor(); // Whole note = 384 ticks at 96
+        match length {
+            Duration::Division(value) if (1..=mml::MAX_DIVISION).contains(&value) => Some(if self.options.dialect.exact_lengths {
+                ExactTicks::new(semibreve as u64, value as u64)
+            } else {
+                ExactTicks::whole(semibreve / value) // C++ formula: floor(semibreve/value)
+            }),
+            Duration::Ticks(ticks) if (1..=mml::MAX_TICK_LENGTH).contains(&ticks) => Some(self.exact(ticks)),
+            _ => None,
+        }
+    }
+
+    /// `ticks` at the default timebase, exactly at this one
+    fn exact(&self, ticks: u32) -> ExactTicks {
+        ExactTicks::new(ticks as u64 * self.timebase as u64, timing::DEFAULT_TIMEBASE as u64)
+    }
+
+    /// `ticks` at the default timebase, floored at this one
+    fn scaled(&self, ticks: u32) -> u32 {
+        self.exact(ticks).floor()
+    }
+
+    /// Time of a program or controller `offset` ticks in, no later than the
+    /// end of the lead-in
+    fn setup_time(&self, offset: u32) -> u32 {
+        self.scaled(offset.min(self.options.lead_in))
+    }
+
+    /// Applies dots: `* 1.5` in float like C++, or exactly with every dot
+    /// adding half the previous one
+    fn dotted(&self, base: ExactTicks, dots: u8) -> ExactTicks {
+        if dots == 0 {
+            base
+        } else if !self.options.dialect.exact_lengths {
+            ExactTicks::whole((base.floor() as f32 * 1.5) as u32) // Dotted notes
+        } else {
+            // Past sixteen dots the additions are far below a tick
+            let dots = dots.min(16) as u32;
+            base.scale((1 << (dots + 1)) - 1, 1 << dots)
+        }
+    }
+
+    /// `12 * octave + base`, plus the accidental (C++ lines 208-215)
+    fn note_number(&self, name: char, accidental: Option<Accidental>, octave: i32) -> i32 {
+        let mut note = 0i32;
+        if let Some(&base_note) = self.sound_map.get(&name) {
+            note = (12 * octave) + base_note;
+        }
+        match accidental {
+            Some(Accidental::Sharp) => note += 1,
+            Some(Accidental::Flat) => note -= 1,
+            None => {}
+        }
+        note
+    }
+
+    /// `n` value, or note 0 with a message when missing or too high
+    fn absolute_note(&self, value: Option<u32>) -> (i32, Option<String>) {
+        match value {
+            Some(value) if value <= self.max_note as u32 => (value as i32, None),
+            Some(value) => (0, Some(format!("note number {} is above {}; n0 is played", value, self.max_note))),
+            None => (0, Some("'n' without a note number plays n0".to_string())),
+        }
+    }
+}
+
+/// Text, tempo, signature and SysEx events that open the file, all at tick 0
+pub fn header_events(options: &ConversionOptions) -> Vec<Box<dyn TrackEvent>> {
+    let mut events: Vec<Box<dyn TrackEvent>> = Vec::new();
+    if let Some(copyright) = &options.copyright {
+        events.push(Box::new(Copyright::new(copyright.clone())));
+    }
+    if let Some(text) = &options.text {
+        events.push(Box::new(MetaText::new(text.clone())));
+    }
+    events.push(Box::new(Tempo::new(midi::DEFAULT_TEMPO_MICROSECONDS)));
+    if let Some((numerator, denominator)) = options.time_signature {
+        events.push(Box::new(TimeSignature::new(numerator, denominator)));
+    }
+    if let Some((sharps, minor)) = options.key_signature {
+        events.push(Box::new(KeySignature::new(sharps, minor)));
+    }
+    if options.gs_reset {
+        events.push(Box::new(SysEx::new(sysex::YOKOSO_SYSEX_DATA.to_vec())));
+    }
+    events
+}
+
+/// Where a part's notes go: its channel and, for drum parts, the kit map
+#[derive(Debug, Clone, Copy)]
+struct PartOutput<'a> {
+    channel: u8,
+    drums: Option<&'a DrumMap>,
+    /// Notes the instrument plays, before drum mapping
+    range: Option<&'a RangeInclusive<u8>>,
+}
+
+/// How long a released note sounds, set by `q`
+#[derive(Debug, Clone, Copy)]
+enum Gate {
+    /// `q1`-`q8`: eighths of the note length
+    Eighths(u32),
+    /// `q%n`: release `n` ticks early
+    Cut(u32),
+}
+
+impl Gate {
+    /// Sounding part of `tick`, at least one tick
+    fn sounding(self, tick: u32) -> u32 {
+        let sounding = match self {
+            Gate::Eighths(eighths) => tick * eighths / mml::GATE_STEPS,
+            Gate::Cut(cut) => tick.saturating_sub(cut),
+        };
+        sounding.max(1).min(tick)
+    }
+}
+
+/// Applies `<` or `>`, returning a message when the octave is already at
+/// its limit
+fn shift_octave(octave: &mut i32, shift: OctaveShift) -> Option<String> {
+    match shift {
+        OctaveShift::Down if *octave <= 0 => {
+            *octave = 0;
+            Some("'<' at octave 0 was ignored".to_string())
+        }
+        OctaveShift::Down => {
+            *octave -= 1;
+            None
+        }
+        OctaveShift::Up if *octave >= MAX_OCTAVE as i32 => {
+            *octave = MAX_OCTAVE as i32;
+            Some(format!("'>' at octave {} leaves octave {}", octave, MAX_OCTAVE))
+        }
+        OctaveShift::Up => {
+            *octave += 1;
+            None
+        }
+    }
+}
+
+fn length_message(value: Duration, outcome: &str) -> String {
+    match value {
+        Duration::Division(0) => format!("length 0 {}", outcome),
+        Duration::Division(value) => format!("length {} is above {} and {}", value, mml::MAX_DIVISION, outcome),
+        Duration::Ticks(0) => format!("length %0 {}", outcome),
+        Duration::Ticks(ticks) => format!("length %{} is above %{} and {}", ticks, mml::MAX_TICK_LENGTH, outcome),
+    }
+}
+
+/// A tick count kept as an exact fraction, so odd divisions and tuplets
+/// carry their rounding from note to note instead of drifting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ExactTicks {
+    num: u64,
+    den: u64,
+}
+
+impl ExactTicks {
+    /// Largest denominator kept; finer fractions are rounded down
+    const MAX_DENOMINATOR: u64 = 1 << 24;
+
+    fn new(num: u64, den: u64) -> Self {
+        Self::reduce(num as u128, den as u128)
+    }
+
+    fn whole(ticks: u32) -> Self {
+        ExactTicks { num: ticks as u64, den: 1 }
+    }
+
+    fn reduce(mut num: u128, mut den: u128) -> Self {
+        let divisor = gcd(num, den).max(1);
+        num /= divisor;
+        den /= divisor;
+        if den > Self::MAX_DENOMINATOR as u128 {
+            num = num * Self::MAX_DENOMINATOR as u128 / den;
+            den = Self::MAX_DENOMINATOR as u128;
+        }
+        ExactTicks { num: num.min(u64::MAX as u128) as u64, den: den as u64 }
+    }
+
+    fn scale(self, num: u64, den: u64) -> Self {
+        Self::reduce(self.num as u128 * num as u128, self.den as u128 * den as u128)
+    }
+
+    fn floor(self) -> u32 {
+        (self.num / self.den).min(u32::MAX as u64) as u32
+    }
+
+    /// Adds `duration` to this carry and takes out the whole ticks
+    fn advance(&mut self, duration: ExactTicks) -> u32 {
+        let total = Self::reduce(
+            self.num as u128 * duration.den as u128 + duration.num as u128 * self.den as u128,
+            self.den as u128 * duration.den as u128,
+        );
+        let ticks = total.floor();
+        *self = ExactTicks::new(total.num - ticks as u64 * total.den, total.den);
+        ticks
+    }
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Open tuplets, innermost last, with each one's share and members left
+#[derive(Default)]
+struct Tuplets(Vec<(ExactTicks, usize)>);
+
+impl Tuplets {
+    fn open(&mut self, duration: ExactTicks, members: usize) {
+        self.0.push((duration.scale(1, members as u64), members));
+    }
+
+    /// Length of the next member of the innermost tuplet, closing every
+    /// tuplet that has no members left
+    fn take_share(&mut self) -> Option<ExactTicks> {
+        let (share, left) = self.0.last_mut()?;
+        let share = *share;
+        *left -= 1;
+        while matches!(self.0.last(), Some((_, 0))) {
+            self.0.pop();
+        }
+        Some(share)
+    }
+}
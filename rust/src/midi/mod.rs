@@ -0,0 +1,8 @@
+//! Standard MIDI File input
+//!
+//! The converter writes MIDI through `YksConverter`; this module reads it
+//! back into the same `TrackEvent` types.
+
+pub mod reader;
+
+pub use reader::{read, MidiFile, ReadError, ReadErrorKind};
@@ -0,0 +1,313 @@
+//! Parses Standard MIDI Files into `TrackEvent`s
+
+use crate::constants::{meta, midi};
+use crate::track_event::*;
+use std::fmt;
+
+/// A parsed Standard MIDI File
+pub struct MidiFile {
+    /// 0 (one track), 1 (simultaneous tracks) or 2 (independent tracks)
+    pub format: u16,
+    /// Ticks per quarter note
+    pub timebase: u16,
+    /// Events of each MTrk chunk in file order, timed from the start of the
+    /// file rather than by delta
+    pub tracks: Vec<Vec<Box<dyn TrackEvent>>>,
+}
+
+impl fmt::Debug for MidiFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tracks: Vec<Vec<(u32, String)>> = self.tracks.iter()
+            .map(|track| track.iter().map(|event| (event.lead_time(), event.value())).collect())
+            .collect();
+        f.debug_struct("MidiFile")
+            .field("format", &self.format)
+            .field("timebase", &self.timebase)
+            .field("tracks", &tracks)
+            .finish()
+    }
+}
+
+/// Why a file could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    /// The file does not start with an `MThd` chunk
+    MissingHeader,
+    /// The header chunk is shorter than six bytes
+    ShortHeader,
+    /// Format other than 0, 1 or 2
+    UnsupportedFormat(u16),
+    /// SMPTE frame-based timing, or a timebase of zero
+    UnsupportedTimebase(u16),
+    /// The file or a chunk ends in the middle of an event
+    UnexpectedEnd,
+    /// A variable-length quantity longer than four bytes
+    VarLenTooLong,
+    /// A data byte with no earlier channel message to repeat the status of
+    MissingStatus,
+    /// A status byte that cannot appear in a file, such as 0xF1
+    InvalidStatus(u8),
+    /// Fewer `MTrk` chunks than the header announces
+    MissingTracks { expected: u16, found: u16 },
+}
+
+/// A malformed or truncated file, with the offset of the offending byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadError {
+    pub kind: ReadErrorKind,
+    /// Byte offset from the start of the file
+    pub offset: usize,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {}: ", self.offset)?;
+        match self.kind {
+            ReadErrorKind::MissingHeader => write!(f, "not a MIDI file (no MThd chunk)"),
+            ReadErrorKind::ShortHeader => write!(f, "header chunk shorter than 6 bytes"),
+            ReadErrorKind::UnsupportedFormat(format) => write!(f, "unsupported format {}", format),
+            ReadErrorKind::UnsupportedTimebase(division) => {
+                write!(f, "unsupported timebase 0x{:04x}", division)
+            }
+            ReadErrorKind::UnexpectedEnd => write!(f, "unexpected end of data"),
+            ReadErrorKind::VarLenTooLong => write!(f, "variable-length quantity over 4 bytes"),
+            ReadErrorKind::MissingStatus => write!(f, "data byte without a status"),
+            ReadErrorKind::InvalidStatus(status) => write!(f, "invalid status byte 0x{:02x}", status),
+            ReadErrorKind::MissingTracks { expected, found } => {
+                write!(f, "header announces {} tracks, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Reads a Standard MIDI File
+///
+/// Chunks other than `MThd` and `MTrk` are skipped, as are bytes after an
+/// End of Track. Running status carries across meta and SysEx events.
+/// Messages without a dedicated event type (aftertouch, pitch bend, escaped
+/// SysEx and unknown meta events) are kept as [`RawEvent`] with their raw
+/// bytes, so every event writes back exactly as read.
+///
+/// # Examples
+///
+/// ```
+/// use yks_converter::{midi, YksConverter};
+///
+/// let buffer = YksConverter::new("MML@t120l4cde,,;".to_string(), 1).to_buffer_result().unwrap();
+/// let file = midi::read(buffer.as_slice()).unwrap();
+/// assert_eq!((file.format, file.timebase, file.tracks.len()), (1, 96, 3));
+/// ```
+pub fn read(bytes: &[u8]) -> Result<MidiFile, ReadError> {
+    let mut cursor = Cursor { bytes, pos: 0, end: bytes.len() };
+
+    if cursor.take(4).ok() != Some(midi::HEADER_CHUNK.as_bytes()) {
+        return Err(ReadError { kind: ReadErrorKind::MissingHeader, offset: 0 });
+    }
+    let length = cursor.u32()? as usize;
+    if length < 6 {
+        return Err(cursor.error_at(ReadErrorKind::ShortHeader, 4));
+    }
+    let header_end = cursor.pos.saturating_add(length);
+    let format = cursor.u16()?;
+    if format > 2 {
+        return Err(cursor.error_at(ReadErrorKind::UnsupportedFormat(format), cursor.pos - 2));
+    }
+    let track_count = cursor.u16()?;
+    let timebase = cursor.u16()?;
+    if timebase == 0 || timebase & 0x8000 != 0 {
+        return Err(cursor.error_at(ReadErrorKind::UnsupportedTimebase(timebase), cursor.pos - 2));
+    }
+    cursor.skip_to(header_end)?;
+
+    let mut tracks = Vec::with_capacity(track_count as usize);
+    while tracks.len() < track_count as usize {
+        if cursor.pos == bytes.len() {
+            let kind = ReadErrorKind::MissingTracks { expected: track_count, found: tracks.len() as u16 };
+            return Err(cursor.error(kind));
+        }
+        let id = cursor.take(4)?;
+        let length = cursor.u32()? as usize;
+        let chunk_end = cursor.pos.saturating_add(length);
+        if id == midi::TRACK_CHUNK.as_bytes() {
+            if chunk_end > bytes.len() {
+                return Err(cursor.error_at(ReadErrorKind::UnexpectedEnd, bytes.len()));
+            }
+            let mut track = Cursor { bytes, pos: cursor.pos, end: chunk_end };
+            tracks.push(track.events()?);
+        }
+        cursor.skip_to(chunk_end)?;
+    }
+
+    Ok(MidiFile { format, timebase, tracks })
+}
+
+/// Read position within one chunk of the file
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn error(&self, kind: ReadErrorKind) -> ReadError {
+        ReadError { kind, offset: self.pos }
+    }
+
+    fn error_at(&self, kind: ReadErrorKind, offset: usize) -> ReadError {
+        ReadError { kind, offset }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ReadError> {
+        if count > self.end - self.pos {
+            return Err(self.error_at(ReadErrorKind::UnexpectedEnd, self.end));
+        }
+        let taken = &self.bytes[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(taken)
+    }
+
+    fn skip_to(&mut self, pos: usize) -> Result<(), ReadError> {
+        if pos > self.end {
+            return Err(self.error_at(ReadErrorKind::UnexpectedEnd, self.end));
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    fn byte(&mut self) -> Result<u8, ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ReadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ReadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a variable-length quantity of at most four bytes
+    fn var_len(&mut self) -> Result<u32, ReadError> {
+        let start = self.pos;
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error_at(ReadErrorKind::VarLenTooLong, start))
+    }
+
+    /// Reads the events of an `MTrk` chunk up to its End of Track
+    fn events(&mut self) -> Result<Vec<Box<dyn TrackEvent>>, ReadError> {
+        let mut events = Vec::new();
+        let mut time = 0u32;
+        let mut running = None;
+
+        while self.pos < self.end {
+            time = time.saturating_add(self.var_len()?);
+            let at = self.pos;
+            let mut status = self.byte()?;
+            if status < 0x80 {
+                status = running.ok_or_else(|| self.error_at(ReadErrorKind::MissingStatus, at))?;
+                self.pos = at;
+            }
+
+            let mut event = match status {
+                0x80..=0xef => {
+                    running = Some(status);
+                    self.channel_event(status)?
+                }
+                0xf0 => {
+                    let length = self.var_len()? as usize;
+                    Box::new(SysEx::new(self.take(length)?.to_vec()))
+                }
+                0xf7 => {
+                    let length = self.var_len()? as usize;
+                    self.take(length)?;
+                    Box::new(RawEvent::new(self.bytes[at..self.pos].to_vec()))
+                }
+                0xff => self.meta_event(at)?,
+                _ => return Err(self.error_at(ReadErrorKind::InvalidStatus(status), at)),
+            };
+
+            event.set_lead_time(time);
+            let ended = event.meta_type() == Some(meta::END_OF_TRACK);
+            events.push(event);
+            if ended {
+                break;
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Reads the data bytes of a channel message with `status`
+    fn channel_event(&mut self, status: u8) -> Result<Box<dyn TrackEvent>, ReadError> {
+        let channel = (status & 0x0f) + 1;
+        let data_length = match status & 0xf0 {
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        };
+        let at = self.pos;
+        let data = self.take(data_length)?;
+        if let Some(offset) = data.iter().position(|&byte| byte >= 0x80) {
+            return Err(self.error_at(ReadErrorKind::InvalidStatus(data[offset]), at + offset));
+        }
+
+        Ok(match status & 0xf0 {
+            0x80 => Box::new(NoteOff::new(channel, data[0], data[1])),
+            0x90 => Box::new(NoteOn::new(channel, data[0], data[1])),
+            0xb0 => Box::new(ControlChange::new(channel, data[0], data[1])),
+            0xc0 => Box::new(ProgramChange::new(channel, data[0])),
+            _ => {
+                let mut raw = vec![status];
+                raw.extend_from_slice(data);
+                Box::new(RawEvent::new(raw))
+            }
+        })
+    }
+
+    /// Reads a meta event whose 0xFF status is at `at`
+    fn meta_event(&mut self, at: usize) -> Result<Box<dyn TrackEvent>, ReadError> {
+        let kind = self.byte()?;
+        let length = self.var_len()? as usize;
+        let data = self.take(length)?;
+        let text = || String::from_utf8(data.to_vec()).ok();
+
+        let event: Option<Box<dyn TrackEvent>> = match (kind, length) {
+            (0x01, _) => text().map(|text| Box::new(MetaText::new(text)) as Box<dyn TrackEvent>),
+            (0x02, _) => text().map(|text| Box::new(Copyright::new(text)) as Box<dyn TrackEvent>),
+            (0x03, _) => text().map(|text| Box::new(TrackName::new(text)) as Box<dyn TrackEvent>),
+            (0x04, _) => text().map(|text| Box::new(InstrumentName::new(text)) as Box<dyn TrackEvent>),
+            (0x05, _) => text().map(|text| Box::new(Lyric::new(text)) as Box<dyn TrackEvent>),
+            (0x06, _) => text().map(|text| Box::new(Marker::new(text)) as Box<dyn TrackEvent>),
+            (0x07, _) => text().map(|text| Box::new(CuePoint::new(text)) as Box<dyn TrackEvent>),
+            (meta::END_OF_TRACK, 0) => Some(Box::new(EndOfTrack::new())),
+            (meta::TEMPO, 3) => {
+                let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                Some(Box::new(Tempo::new(tempo)))
+            }
+            // Only the 24-clock, eight-32nds form TimeSignature writes
+            (0x58, 4) if data[1] < 8 && data[2..] == [24, 8] => {
+                Some(Box::new(TimeSignature::new(data[0], 1 << data[1])))
+            }
+            (0x59, 2) if (data[0] as i8).unsigned_abs() <= 7 && data[1] <= 1 => {
+                Some(Box::new(KeySignature::new(data[0] as i8, data[1] == 1)))
+            }
+            _ => None,
+        };
+
+        let raw = self.bytes[at..self.pos].to_vec();
+        Ok(event.unwrap_or_else(|| match kind {
+            0x7f => Box::new(SeqSpec::new(raw)),
+            _ => Box::new(RawEvent::new(raw)),
+        }))
+    }
+}
@@ -0,0 +1,527 @@
+//! Converts Standard MIDI Files back into Mabinogi MML
+//!
+//! The inverse of the tick model in `Mf2tt2mf::parse_track`: a whole note is
+//! 384 ticks, `l8` is `384 / 8`, a dot multiplies by 1.5 and velocity is
+//! eight times the volume. Feeding the result back through `YksConverter`
+//! plays the same notes.
+
+use crate::constants::{event_timing, midi, mml, timing};
+use crate::errors::ConversionError;
+use crate::midi::reader::{self, MidiFile};
+use crate::track_event::TrackEvent;
+use crate::voices::{self, Note, VoiceStrategy};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Note letters for each pitch class, sharps written `+`
+const NOTE_NAMES: [&str; 12] = ["c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b"];
+
+/// Highest MML pitch, `o8c`; MIDI notes are 12 above
+const MAX_PITCH: u8 = 96;
+
+/// Rests and notes longer than this are split into whole notes first, so
+/// the length search stays small
+const LONGEST_SEARCH: u32 = 4 * timing::TICKS_PER_WHOLE_NOTE;
+
+/// What a part plays next, with lengths in ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token {
+    Rest(u32),
+    /// `tied` carries the note on past a tempo change
+    Note { pitch: u8, velocity: u8, length: u32, tied: bool },
+    /// Beats per minute
+    Tempo(u32),
+}
+
+/// A note left out of the MML because every line was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedNote {
+    /// Index of the MIDI track
+    pub track: usize,
+    /// MIDI channel, numbered from 1
+    pub channel: u8,
+    /// Timed from the start of the MML, at 96 ticks per quarter note
+    pub note: Note,
+}
+
+/// A note cut short because its line moved on to the next note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortenedNote {
+    /// Index of the MIDI track
+    pub track: usize,
+    /// MIDI channel, numbered from 1
+    pub channel: u8,
+    /// The note as it was in the MIDI file
+    pub note: Note,
+    /// Where the note now stops
+    pub end: u32,
+}
+
+/// Everything `MidiToMml` could not keep
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImportReport {
+    pub dropped: Vec<DroppedNote>,
+    /// Notes cut short by more than a legato overlap
+    pub shortened: Vec<ShortenedNote>,
+}
+
+/// Converts MIDI files into one `MML@a,b,c;` string per performer
+///
+/// Each MIDI channel becomes a performer. Every track playing on it is split
+/// into up to three monophonic lines (see [`set_voices`](Self::set_voices)),
+/// each line becoming a part; a channel with more than three parts is spread
+/// across several performers. Tempo changes from every track go in the first
+/// part.
+///
+/// # Examples
+///
+/// ```
+/// use yks_converter::{MidiToMml, YksConverter};
+///
+/// let buffer = YksConverter::new("MML@t150l8cdefgab>c,,;".to_string(), 1).to_buffer_result().unwrap();
+/// let mml = MidiToMml::new().convert(buffer.as_slice()).unwrap();
+/// assert_eq!(mml, vec!["MML@t150l8cdefgab>c,,;"]);
+/// ```
+pub struct MidiToMml {
+    lead_in: u32,
+    voices: usize,
+    strategy: VoiceStrategy,
+}
+
+impl MidiToMml {
+    pub fn new() -> Self {
+        MidiToMml {
+            lead_in: event_timing::TRACK_START_TIME,
+            voices: midi::TRACKS_PER_MML as usize,
+            strategy: VoiceStrategy::default(),
+        }
+    }
+
+    /// Splits each track into at most `voices` parts (1-3) with `strategy`,
+    /// three by the highest notes unless set
+    pub fn set_voices(&mut self, voices: usize, strategy: VoiceStrategy) {
+        self.voices = voices.clamp(1, midi::TRACKS_PER_MML as usize);
+        self.strategy = strategy;
+    }
+
+    /// Ticks of silence (at 96 per quarter note) dropped from the start,
+    /// 384 by default to undo the converter's own lead-in. Never more than
+    /// the silence before the first note is dropped.
+    pub fn set_lead_in(&mut self, ticks: u32) {
+        self.lead_in = ticks;
+    }
+
+    /// Reads a Standard MIDI File and converts it
+    pub fn convert(&self, bytes: &[u8]) -> Result<Vec<String>, ConversionError> {
+        self.convert_with_report(bytes).map(|(performers, _)| performers)
+    }
+
+    /// Like [`convert`](Self::convert), also listing the notes no part had
+    /// room for
+    pub fn convert_with_report(&self, bytes: &[u8]) -> Result<(Vec<String>, ImportReport), ConversionError> {
+        let file = reader::read(bytes).map_err(ConversionError::MidiReadFailed)?;
+        Ok(self.convert_file(&file))
+    }
+
+    /// Converts an already parsed file
+    pub fn convert_file(&self, file: &MidiFile) -> (Vec<String>, ImportReport) {
+        let to_quarter96 = |ticks: u32| {
+            let ticks = ticks as u64 * timing::DEFAULT_TIMEBASE as u64;
+            ((ticks + file.timebase as u64 / 2) / file.timebase as u64).min(u32::MAX as u64) as u32
+        };
+
+        let mut lines: Vec<((usize, u8), Vec<Note>)> = Vec::new();
+        let mut tempos = Vec::new();
+        for (index, track) in file.tracks.iter().enumerate() {
+            for (channel, notes) in track_notes(track, &to_quarter96) {
+                lines.push(((index, channel), notes));
+            }
+            for event in track.iter().filter(|event| event.meta_type() == Some(0x51)) {
+                let data = event.to_buffer();
+                let microseconds = u32::from_be_bytes([0, data.get_at(3), data.get_at(4), data.get_at(5)]);
+                tempos.push((to_quarter96(event.lead_time()), microseconds));
+            }
+        }
+
+        let first_note = lines.iter().flat_map(|(_, notes)| notes).map(|note| note.start).min();
+        let offset = first_note.unwrap_or(0).min(self.lead_in);
+
+        // One change per tick, the last written winning, and none that
+        // repeat the tempo already playing
+        tempos.sort_by_key(|&(time, _)| time);
+        let mut tempo_map: Vec<(u32, u32)> = Vec::new();
+        for (time, microseconds) in tempos {
+            let time = time.saturating_sub(offset);
+            let bpm = ((60_000_000 + microseconds as u64 / 2) / microseconds.max(1) as u64).max(1) as u32;
+            if tempo_map.last().is_some_and(|&(last, _)| last == time) {
+                tempo_map.pop();
+            }
+            tempo_map.push((time, bpm));
+        }
+        let mut playing = midi::DEFAULT_TEMPO_MICROSECONDS;
+        tempo_map.retain(|&(_, bpm)| {
+            let changed = 60_000_000 / bpm != playing;
+            playing = 60_000_000 / bpm;
+            changed
+        });
+
+        // Lines grouped by channel in order of appearance, three to a
+        // performer; a track with no notes keeps its empty part
+        let mut report = ImportReport::default();
+        let mut channels: Vec<(u8, Vec<Vec<Note>>)> = Vec::new();
+        for ((track, channel), notes) in lines {
+            let notes: Vec<Note> = notes.iter()
+                .map(|note| Note { start: note.start - offset, end: note.end - offset, ..*note })
+                .collect();
+            let (mut parts, dropped) = voices::separate(&notes, self.voices, self.strategy);
+            report.dropped.extend(dropped.into_iter().map(|note| DroppedNote { track, channel, note }));
+            for kept in parts.iter().flatten() {
+                let written = notes.iter().find(|note| note.start == kept.start && note.pitch == kept.pitch);
+                if let Some(&note) = written.filter(|note| note.end > kept.end + voices::LEGATO_OVERLAP) {
+                    report.shortened.push(ShortenedNote { track, channel, note, end: kept.end });
+                }
+            }
+            if parts.is_empty() {
+                parts.push(Vec::new());
+            }
+            match channels.iter_mut().find(|(known, _)| *known == channel) {
+                Some((_, known)) => known.extend(parts),
+                None => channels.push((channel, parts)),
+            }
+        }
+
+        let mut lengths = Lengths::new();
+        let mut performers = Vec::new();
+        for (_, parts) in channels {
+            let chunks = parts.chunks(midi::TRACKS_PER_MML as usize);
+            for chunk in chunks.filter(|chunk| chunk.iter().any(|notes| !notes.is_empty())) {
+                let mut texts = Vec::with_capacity(midi::TRACKS_PER_MML as usize);
+                for (part, notes) in chunk.iter().enumerate() {
+                    let tempos = if performers.is_empty() && part == 0 { &tempo_map[..] } else { &[] };
+                    texts.push(write_part(&tokens(notes, tempos), &mut lengths));
+                }
+                texts.resize(midi::TRACKS_PER_MML as usize, String::new());
+                performers.push(format!("MML@{};", texts.join(",")));
+            }
+        }
+        (performers, report)
+    }
+}
+
+impl Default for MidiToMml {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Notes of each channel a track plays on, in order of the channel's first
+/// message; a channel with only controllers has no notes
+pub(crate) fn track_notes(track: &[Box<dyn TrackEvent>], to_quarter96: &dyn Fn(u32) -> u32) -> Vec<(u8, Vec<Note>)> {
+    let mut channels: Vec<(u8, Vec<Note>)> = Vec::new();
+    let mut sounding: HashMap<(u8, u8), Vec<(u32, u8)>> = HashMap::new();
+    let mut end = 0;
+
+    for event in track {
+        let time = to_quarter96(event.lead_time());
+        end = end.max(time);
+        let data = event.to_buffer();
+        let status = data.get_at(0);
+        if !(0x80..=0xef).contains(&status) {
+            continue;
+        }
+        let channel = (status & 0x0f) + 1;
+        let index = match channels.iter().position(|(known, _)| *known == channel) {
+            Some(index) => index,
+            None => {
+                channels.push((channel, Vec::new()));
+                channels.len() - 1
+            }
+        };
+
+        let (pitch, velocity) = (data.get_at(1), data.get_at(2));
+        match status & 0xf0 {
+            0x90 if velocity > 0 => sounding.entry((channel, pitch)).or_default().push((time, velocity)),
+            0x80 | 0x90 => {
+                let started = sounding.get_mut(&(channel, pitch)).filter(|starts| !starts.is_empty());
+                if let Some(starts) = started {
+                    let (start, velocity) = starts.remove(0);
+                    channels[index].1.push(Note { start, end: time, pitch, velocity });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Notes never released end with the track
+    for ((channel, pitch), starts) in sounding {
+        let index = channels.iter().position(|(known, _)| *known == channel).unwrap_or(0);
+        for (start, velocity) in starts {
+            channels[index].1.push(Note { start, end, pitch, velocity });
+        }
+    }
+    for (_, notes) in &mut channels {
+        notes.sort_by_key(|note| (note.start, Reverse(note.pitch)));
+    }
+    channels
+}
+
+/// Rests, notes and tempo changes of one part, lengthening any note too
+/// short to write and catching up on the following rest
+pub(crate) fn tokens(notes: &[Note], tempos: &[(u32, u32)]) -> Vec<Token> {
+    // Spans to play up to: rests before each note, the notes, then rests
+    // until the last tempo change
+    let mut spans: Vec<(u32, Option<Note>)> = Vec::with_capacity(notes.len() * 2 + 1);
+    for note in notes {
+        spans.push((note.start, None));
+        spans.push((note.end, Some(*note)));
+    }
+    spans.push((tempos.last().map_or(0, |&(at, _)| at), None));
+
+    // Split the spans where the tempo changes
+    let mut pieces: Vec<Token> = Vec::new();
+    let mut changes = tempos.iter().copied().peekable();
+    let mut time = 0;
+    for (to, note) in spans {
+        loop {
+            while let Some((_, bpm)) = changes.next_if(|&(at, _)| at <= time) {
+                pieces.push(Token::Tempo(bpm));
+            }
+            if time >= to {
+                break;
+            }
+            let until = changes.peek().map_or(to, |&(at, _)| at.min(to));
+            pieces.push(match note {
+                Some(note) => Token::Note { pitch: note.pitch, velocity: note.velocity, length: until, tied: until < to },
+                None => Token::Rest(until),
+            });
+            time = until;
+        }
+    }
+
+    // Piece ends become lengths; the shortest length is two ticks
+    let mut tokens = Vec::with_capacity(pieces.len());
+    let mut cursor = 0u32;
+    for piece in pieces {
+        match piece {
+            Token::Rest(end) if end >= cursor + 2 => {
+                tokens.push(Token::Rest(end - cursor));
+                cursor = end;
+            }
+            Token::Rest(end) => {
+                if let Some(Token::Note { length, tied: false, .. }) = tokens.last_mut() {
+                    *length += end.saturating_sub(cursor);
+                    cursor = cursor.max(end);
+                }
+            }
+            Token::Note { pitch, velocity, length: end, tied } => {
+                let length = end.saturating_sub(cursor).max(2);
+                tokens.push(Token::Note { pitch, velocity, length, tied });
+                cursor += length;
+            }
+            Token::Tempo(_) => tokens.push(piece),
+        }
+    }
+    tokens
+}
+
+/// Spells tick lengths with the fewest characters
+pub(crate) struct Lengths {
+    /// Ticks of every length MML can write, longest first, with the
+    /// shortest spelling: `1`-`192` floored like the converter, and each
+    /// with one dot
+    table: Vec<(u32, String)>,
+    memo: HashMap<(u32, usize, u32), Vec<u32>>,
+}
+
+impl Lengths {
+    pub(crate) fn new() -> Self {
+        let mut table: Vec<(u32, String, bool)> = Vec::new();
+        for division in 1..=mml::MAX_DIVISION {
+            let base = timing::TICKS_PER_WHOLE_NOTE / division;
+            let dotted = (base as f32 * 1.5) as u32;
+            // Between spellings of one length, prefer a division that fits
+            // the whole note exactly: `32` rather than `30`
+            let exact = timing::TICKS_PER_WHOLE_NOTE.is_multiple_of(division);
+            for (ticks, text) in [(base, division.to_string()), (dotted, format!("{}.", division))] {
+                match table.iter_mut().find(|(known, ..)| *known == ticks) {
+                    Some(entry) if (text.len(), !exact) < (entry.1.len(), !entry.2) => *entry = (ticks, text, exact),
+                    Some(_) => {}
+                    None => table.push((ticks, text, exact)),
+                }
+            }
+        }
+        table.sort_by_key(|&(ticks, ..)| Reverse(ticks));
+        let table = table.into_iter().map(|(ticks, text, _)| (ticks, text)).collect();
+        Lengths { table, memo: HashMap::new() }
+    }
+
+    /// Number written for `length` ticks
+    pub(crate) fn text(&self, length: u32) -> &str {
+        self.written(length).unwrap_or("")
+    }
+
+    /// Number written for `length` ticks, or `None` when no single number
+    /// makes it
+    pub(crate) fn written(&self, length: u32) -> Option<&str> {
+        self.table.iter().find(|(ticks, _)| *ticks == length).map(|(_, text)| text.as_str())
+    }
+
+    /// Every length a single number writes, longest first
+    pub(crate) fn ticks(&self) -> impl Iterator<Item = u32> + '_ {
+        self.table.iter().map(|&(ticks, _)| ticks)
+    }
+
+    /// Lengths tied together to make `ticks`, longest first, spending the
+    /// fewest characters when each costs `overhead` plus its number and
+    /// `default` needs no number
+    fn split(&mut self, ticks: u32, overhead: usize, default: u32) -> Vec<u32> {
+        if let Some(lengths) = self.memo.get(&(ticks, overhead, default)) {
+            return lengths.clone();
+        }
+
+        let mut lengths = Vec::new();
+        let mut rest = ticks;
+        while rest > LONGEST_SEARCH {
+            lengths.push(timing::TICKS_PER_WHOLE_NOTE);
+            rest -= timing::TICKS_PER_WHOLE_NOTE;
+        }
+
+        let cost = |length: u32, text: &str| overhead + if length == default { 0 } else { text.len() };
+        let mut best: Vec<Option<(usize, u32)>> = vec![None; rest as usize + 1];
+        best[0] = Some((0, 0));
+        for total in 1..=rest as usize {
+            best[total] = self.table.iter()
+                .filter(|(length, _)| *length as usize <= total)
+                .filter_map(|(length, text)| {
+                    best[total - *length as usize].map(|(spent, _)| (spent + cost(*length, text), *length))
+                })
+                .min_by_key(|&(spent, length)| (spent, Reverse(length)));
+        }
+
+        let mut total = rest as usize;
+        while let Some((_, length)) = best[total].filter(|_| total > 0) {
+            lengths.push(length);
+            total -= length as usize;
+        }
+        lengths.sort_by_key(|&length| Reverse(length));
+        self.memo.insert((ticks, overhead, default), lengths.clone());
+        lengths
+    }
+
+    /// The `l` length that writes `tokens` in the fewest characters, out of
+    /// every length they would otherwise spell out; `extra` adds anything
+    /// else a choice costs
+    pub(crate) fn best_default(&mut self, tokens: &[Token], extra: impl Fn(&mut Lengths, u32) -> usize) -> u32 {
+        let quarter = timing::TICKS_PER_QUARTER_NOTE;
+        let mut candidates = vec![quarter];
+        for (ticks, overhead) in tokens.iter().filter_map(|&token| spelled(token)) {
+            for length in self.split(ticks, overhead, quarter) {
+                if !candidates.contains(&length) {
+                    candidates.push(length);
+                }
+            }
+        }
+        candidates.into_iter()
+            .min_by_key(|&length| {
+                let setting = if length == quarter { 0 } else { 1 + self.text(length).len() };
+                let spent: usize = tokens.iter().map(|&token| self.cost(token, length)).sum();
+                (spent + setting + extra(self, length), length != quarter)
+            })
+            .unwrap_or(quarter)
+    }
+
+    /// Characters the lengths of `token` take with `default` as `l`
+    pub(crate) fn cost(&mut self, token: Token, default: u32) -> usize {
+        let Some((ticks, overhead)) = spelled(token) else { return 0 };
+        self.split(ticks, overhead, default).iter()
+            .map(|&length| overhead + if length == default { 0 } else { self.text(length).len() })
+            .sum()
+    }
+}
+
+/// Ticks of a rest or note and the characters each tied length adds
+/// besides its number
+fn spelled(token: Token) -> Option<(u32, usize)> {
+    match token {
+        Token::Rest(ticks) => Some((ticks, 1)),
+        Token::Note { pitch, length, .. } => Some((length, NOTE_NAMES[(pitch % 12) as usize].len() + 1)),
+        Token::Tempo(_) => None,
+    }
+}
+
+/// Writes one part, choosing the `l` length that makes it shortest
+fn write_part(tokens: &[Token], lengths: &mut Lengths) -> String {
+    let default = lengths.best_default(tokens, |_, _| 0);
+    render(tokens, default, lengths)
+}
+
+/// Writes `tokens` with `default` as the `l` length, set after any tempo
+/// changes that open the part
+pub(crate) fn render(tokens: &[Token], default: u32, lengths: &mut Lengths) -> String {
+    let quarter = timing::TICKS_PER_QUARTER_NOTE;
+    let mut result = String::new();
+    let mut octave = mml::DEFAULT_OCTAVE as u8;
+    let mut volume = mml::DEFAULT_VOLUME as u8;
+    let leading_tempos = tokens.iter().take_while(|token| matches!(token, Token::Tempo(_))).count();
+    for (i, &token) in tokens.iter().enumerate() {
+        if i == leading_tempos && default != quarter {
+            result.push('l');
+            result.push_str(lengths.text(default));
+        }
+        match token {
+            Token::Tempo(bpm) => result.push_str(&format!("t{}", bpm)),
+            Token::Rest(ticks) => {
+                for length in lengths.split(ticks, 1, default) {
+                    result.push('r');
+                    if length != default {
+                        result.push_str(lengths.text(length));
+                    }
+                }
+            }
+            Token::Note { pitch, velocity, length, tied } => {
+                // Out-of-range notes wrap by octaves, as the converter does
+                let mut pitch = pitch;
+                while pitch < 12 {
+                    pitch += 12;
+                }
+                while pitch > MAX_PITCH + 12 {
+                    pitch -= 12;
+                }
+                let pitch = pitch - 12;
+                let name = NOTE_NAMES[(pitch % 12) as usize];
+
+                let target = pitch / 12;
+                let shift = target.abs_diff(octave) as usize;
+                if shift > 1 + target.to_string().len() {
+                    result.push_str(&format!("o{}", target));
+                } else if target > octave {
+                    result.push_str(&">".repeat(shift));
+                } else {
+                    result.push_str(&"<".repeat(shift));
+                }
+                octave = target;
+
+                let level = ((velocity as u32 + 4) / mml::VELOCITY_MULTIPLIER as u32)
+                    .clamp(mml::MIN_VOLUME as u32, mml::MAX_VOLUME as u32) as u8;
+                if level != volume {
+                    result.push_str(&format!("v{}", level));
+                    volume = level;
+                }
+
+                for (j, length) in lengths.split(length, name.len() + 1, default).into_iter().enumerate() {
+                    if j > 0 {
+                        result.push('&');
+                    }
+                    result.push_str(name);
+                    if length != default {
+                        result.push_str(lengths.text(length));
+                    }
+                }
+                if tied {
+                    result.push('&');
+                }
+            }
+        }
+    }
+    result
+}
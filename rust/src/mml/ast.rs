@@ -0,0 +1,295 @@
+//! Token and syntax tree types for MML parts
+
+use crate::constants::mml::DEFAULT_LOOP_COUNT;
+
+/// Byte range into the original MML string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    /// Slice of `source` covered by this span
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+}
+
+/// Accidental attached to a note name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accidental {
+    /// `+` or `#`
+    Sharp,
+    /// `-`
+    Flat,
+}
+
+/// Direction of a relative octave change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OctaveShift {
+    /// `<`
+    Down,
+    /// `>`
+    Up,
+}
+
+/// Meta event written by a quoted text command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextKind {
+    /// `!"..."`
+    Marker,
+    /// `~"..."`
+    Lyric,
+}
+
+/// A written length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duration {
+    /// `4`: a division of the whole note
+    Division(u32),
+    /// `%30`: a number of ticks
+    Ticks(u32),
+}
+
+/// Lexical token kinds
+///
+/// Numeric values hold what the parser understood, not the raw digits:
+/// `l08` is `Some(0)` and `l+8` is `None`, exactly as `parse_track` reads them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// `a`-`g` with optional accidental, length and dots. `note` is lowercase.
+    Note {
+        note: char,
+        accidental: Option<Accidental>,
+        length: Option<Duration>,
+        dots: u8,
+    },
+    /// `r` with optional length and dots
+    Rest { length: Option<Duration>, dots: u8 },
+    /// `l` default length
+    Length { value: Option<Duration>, dots: u8 },
+    /// `o` absolute octave
+    Octave(Option<u32>),
+    /// `<` or `>`
+    OctaveShift(OctaveShift),
+    /// `t` tempo in BPM
+    Tempo(Option<u32>),
+    /// `v` volume
+    Volume(Option<u32>),
+    /// `n` absolute note number
+    AbsoluteNote(Option<u32>),
+    /// `&` following a note or `l`
+    Tie,
+    /// `[` opening a loop
+    LoopStart,
+    /// `]` closing a loop, with optional repeat count
+    LoopEnd(Option<u32>),
+    /// `:` leaving a loop on its last pass
+    LoopBreak,
+    /// `q` gate time: eighths of the length, or with `%` ticks cut from it
+    Gate { value: Option<u32>, ticks: bool },
+    /// `k` sets the transpose, `_` adds to it; the sign goes before the value
+    Transpose { semitones: Option<i32>, relative: bool },
+    /// `'` opening a chord
+    ChordStart,
+    /// `'` closing a chord, with optional length and dots
+    ChordEnd { length: Option<Duration>, dots: u8 },
+    /// `{` opening a tuplet
+    TupletStart,
+    /// `}` closing a tuplet, with optional length and dots
+    TupletEnd { length: Option<Duration>, dots: u8 },
+    /// `!"..."` or `~"..."`; `closed` is false when the part ends first
+    Text { kind: TextKind, closed: bool },
+    /// Text the converter matches but never reads, such as the `+8` in
+    /// `l+8`, a second dot, or `&` after a rest
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Syntax tree node kinds
+///
+/// Ties are folded into the note or `l` they follow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    Note {
+        note: char,
+        accidental: Option<Accidental>,
+        length: Option<Duration>,
+        dots: u8,
+        tied: bool,
+    },
+    Rest {
+        length: Option<Duration>,
+        dots: u8,
+    },
+    Length {
+        value: Option<Duration>,
+        dots: u8,
+        tied: bool,
+    },
+    Octave(Option<u32>),
+    OctaveShift(OctaveShift),
+    Tempo(Option<u32>),
+    Volume(Option<u32>),
+    AbsoluteNote {
+        value: Option<u32>,
+        tied: bool,
+    },
+    /// `[body]count`; the count defaults to 2
+    Loop {
+        body: Vec<Node>,
+        count: Option<u32>,
+    },
+    /// `:` inside a loop; the last pass stops here
+    LoopBreak,
+    /// `q6` or `q%12`
+    Gate {
+        value: Option<u32>,
+        ticks: bool,
+    },
+    /// `k-2` or `_+12`
+    Transpose {
+        semitones: Option<i32>,
+        relative: bool,
+    },
+    /// `'ceg'4`: notes, `n` and `<`/`>` sounding together for one length
+    Chord {
+        notes: Vec<Node>,
+        length: Option<Duration>,
+        dots: u8,
+        tied: bool,
+    },
+    /// `{cde}4`: notes, rests, chords and inner tuplets sharing one length
+    /// equally
+    Tuplet {
+        body: Vec<Node>,
+        length: Option<Duration>,
+        dots: u8,
+    },
+    /// `!"Verse"` or `~"la"`, with the text between the quotes
+    Text {
+        kind: TextKind,
+        text: String,
+    },
+}
+
+impl NodeKind {
+    /// Notes, rests, chords and tuplets; each is one share of a tuplet
+    pub fn takes_time(&self) -> bool {
+        matches!(
+            self,
+            NodeKind::Note { .. }
+                | NodeKind::AbsoluteNote { .. }
+                | NodeKind::Rest { .. }
+                | NodeKind::Chord { .. }
+                | NodeKind::Tuplet { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+/// One comma-separated part of an MML string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub nodes: Vec<Node>,
+    /// Span of the part text, excluding leading whitespace
+    pub span: Span,
+    /// Text inside the part that produced no node
+    pub skipped: Vec<Span>,
+}
+
+impl Track {
+    /// `true` when the part has no text at all (`MML@c,,;` parts 2 and 3)
+    pub fn is_empty(&self) -> bool {
+        self.span.is_empty()
+    }
+
+    /// Nodes in playing order, with loops unrolled. A tuplet is followed by
+    /// its members.
+    pub fn expanded(&self) -> Vec<&Node> {
+        let mut out = Vec::new();
+        expand(&self.nodes, &mut out);
+        out
+    }
+}
+
+/// Appends `nodes` to `out` in playing order, unrolling loops
+pub fn expand<'a>(nodes: &'a [Node], out: &mut Vec<&'a Node>) {
+    for node in nodes {
+        match &node.kind {
+            NodeKind::Loop { body, count } => {
+                let count = count.unwrap_or(DEFAULT_LOOP_COUNT);
+                for pass in 0..count {
+                    let last = pass + 1 == count;
+                    for inner in body {
+                        if inner.kind == NodeKind::LoopBreak {
+                            if last {
+                                break;
+                            }
+                            continue;
+                        }
+                        expand(std::slice::from_ref(inner), out);
+                    }
+                }
+            }
+            NodeKind::Tuplet { body, .. } => {
+                // The tuplet comes first so its members can be scaled
+                out.push(node);
+                expand(body, out);
+            }
+            NodeKind::LoopBreak => {}
+            _ => out.push(node),
+        }
+    }
+}
+
+/// Number of nodes `expand` would produce, saturating
+pub fn expanded_len(nodes: &[Node]) -> usize {
+    nodes.iter().fold(0usize, |total, node| {
+        let len = match &node.kind {
+            NodeKind::Loop { body, count } => {
+                expanded_len(body).saturating_mul(count.unwrap_or(DEFAULT_LOOP_COUNT) as usize)
+            }
+            NodeKind::Tuplet { body, .. } => expanded_len(body).saturating_add(1),
+            NodeKind::LoopBreak => 0,
+            _ => 1,
+        };
+        total.saturating_add(len)
+    })
+}
+
+/// A parsed `MML@...;` string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Score {
+    pub tracks: Vec<Track>,
+    /// Span from `MML@` through the closing `;`
+    pub span: Span,
+}
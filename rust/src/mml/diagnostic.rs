@@ -0,0 +1,148 @@
+//! Parse diagnostics with source positions
+
+use std::fmt;
+
+/// Whether a diagnostic stops conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    /// The MML cannot be converted
+    Error,
+    /// The MML converts, but not exactly as written
+    Warning,
+}
+
+/// Machine-readable diagnostic codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticCode {
+    /// No `MML@` prefix in the string
+    MissingPrefix,
+    /// Character outside the MML alphabet
+    UnknownCharacter,
+    /// More parts than the dialect allows
+    WrongPartCount,
+    /// No closing `;`
+    MissingTerminator,
+    /// `[` or `]` without its partner
+    UnbalancedLoop,
+    /// Loops unroll into too many notes
+    LoopTooLong,
+    /// Unclosed or empty chord, or a command chords cannot hold
+    MalformedChord,
+    /// Unbalanced or empty tuplet, or a loop inside one
+    MalformedTuplet,
+    /// `!"` or `~"` without a closing quote
+    UnterminatedText,
+    /// Text skipped without effect
+    IgnoredText,
+    /// Length outside 1-192, so the default length is used
+    LengthIgnored,
+    /// Tempo of zero or without a value, so no tempo change is emitted
+    TempoIgnored,
+    /// Volume clamped into 1-15
+    VolumeClamped,
+    /// `o` above the highest octave; notes will wrap down
+    OctaveOutOfRange,
+    /// `<` or `>` held at the lowest or highest octave
+    OctaveShiftClamped,
+    /// `n` missing or above the highest note, so note 0 is played
+    NoteNumberOutOfRange,
+    /// Pitch moved by whole octaves into the playable range
+    NoteWrapped,
+    /// Note outside the range of the part's instrument
+    OutsideInstrumentRange,
+    /// `q` without a value, or outside 1-8, so the gate time is unchanged
+    GateIgnored,
+    /// Transpose clamped into range
+    TransposeClamped,
+    /// `k` or `_` without a value, so the transpose is unchanged
+    TransposeIgnored,
+}
+
+impl DiagnosticCode {
+    /// Stable identifier for tooling, e.g. `unknown-character`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticCode::MissingPrefix => "missing-prefix",
+            DiagnosticCode::UnknownCharacter => "unknown-character",
+            DiagnosticCode::WrongPartCount => "wrong-part-count",
+            DiagnosticCode::MissingTerminator => "missing-terminator",
+            DiagnosticCode::UnbalancedLoop => "unbalanced-loop",
+            DiagnosticCode::LoopTooLong => "loop-too-long",
+            DiagnosticCode::MalformedChord => "malformed-chord",
+            DiagnosticCode::MalformedTuplet => "malformed-tuplet",
+            DiagnosticCode::UnterminatedText => "unterminated-text",
+            DiagnosticCode::IgnoredText => "ignored-text",
+            DiagnosticCode::LengthIgnored => "length-ignored",
+            DiagnosticCode::TempoIgnored => "tempo-ignored",
+            DiagnosticCode::VolumeClamped => "volume-clamped",
+            DiagnosticCode::OctaveOutOfRange => "octave-out-of-range",
+            DiagnosticCode::OctaveShiftClamped => "octave-shift-clamped",
+            DiagnosticCode::NoteNumberOutOfRange => "note-number-out-of-range",
+            DiagnosticCode::NoteWrapped => "note-wrapped",
+            DiagnosticCode::OutsideInstrumentRange => "outside-instrument-range",
+            DiagnosticCode::GateIgnored => "gate-ignored",
+            DiagnosticCode::TransposeClamped => "transpose-clamped",
+            DiagnosticCode::TransposeIgnored => "transpose-ignored",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            DiagnosticCode::MissingPrefix
+            | DiagnosticCode::UnknownCharacter
+            | DiagnosticCode::WrongPartCount
+            | DiagnosticCode::MissingTerminator
+            | DiagnosticCode::UnbalancedLoop
+            | DiagnosticCode::LoopTooLong
+            | DiagnosticCode::MalformedChord
+            | DiagnosticCode::MalformedTuplet
+            | DiagnosticCode::UnterminatedText => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A problem found in an MML string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: DiagnosticCode,
+    /// Zero-based part index, `None` when the problem is not inside a part
+    pub track: Option<usize>,
+    /// Byte offset into the full MML string
+    pub offset: usize,
+    /// The offending source text
+    pub text: String,
+    /// Human-readable explanation
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(code: DiagnosticCode, track: Option<usize>, offset: usize, text: &str, message: String) -> Self {
+        Diagnostic {
+            code,
+            track,
+            offset,
+            text: text.to_string(),
+            message,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.code.severity()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(track) = self.track {
+            write!(f, "track {}, ", track + 1)?;
+        }
+        write!(f, "offset {}: {} [{}]", self.offset, self.message, self.code)
+    }
+}
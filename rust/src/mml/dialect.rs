@@ -0,0 +1,57 @@
+//! MML dialects and the syntax each one accepts
+
+use crate::constants::midi;
+
+/// Syntax accepted by the parser
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dialect {
+    /// Most comma-separated parts allowed in one `MML@...;` string
+    pub max_parts: usize,
+    /// `[...]n` repeat blocks with `:` breaks
+    pub loops: bool,
+    /// `'ceg'4` chords sounding several notes in one part
+    pub chords: bool,
+    /// `q` gate time, as eighths (`q6`) or ticks cut (`q%12`)
+    pub gate_time: bool,
+    /// `k` absolute and `_` relative transpose in semitones
+    pub transpose: bool,
+    /// `{cde}4` tuplets, `c%30` tick lengths and `c4..` repeated dots, timed
+    /// exactly with rounding carried from note to note
+    pub exact_lengths: bool,
+    /// `!"Verse"` markers and `~"la"` lyrics
+    pub text_events: bool,
+}
+
+impl Dialect {
+    /// Mabinogi: up to three parts (melody and two chords), no extensions
+    pub fn mabinogi() -> Self {
+        Dialect {
+            max_parts: midi::TRACKS_PER_MML as usize,
+            loops: false,
+            chords: false,
+            gate_time: false,
+            transpose: false,
+            exact_lengths: false,
+            text_events: false,
+        }
+    }
+
+    /// Every syntax extension, with no limit on the number of parts
+    pub fn extended() -> Self {
+        Dialect {
+            max_parts: usize::MAX,
+            loops: true,
+            chords: true,
+            gate_time: true,
+            transpose: true,
+            exact_lengths: true,
+            text_events: true,
+        }
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self::mabinogi()
+    }
+}
@@ -0,0 +1,471 @@
+//! MML lexer
+//!
+//! Produces the same token boundaries as the C++ pattern
+//! `[OTLVNRA-Gotlvnra-g<>][\+\-\#]?[0-9]*\.?&?` applied to the part with
+//! whitespace removed, but keeps byte spans into the original string.
+//! Loop brackets `[`, `]n`, breaks `:`, chord quotes `'`, tuplet braces `{`,
+//! `}n`, gate times `q` and transposes `k`/`_` are tokens of their own; the
+//! parser only lets them through when the dialect enables them. Tick lengths
+//! (`c%30`) and repeated dots change token boundaries, so they are only read
+//! when the dialect has `exact_lengths`; quoted text (`!"Verse"`) likewise
+//! needs `text_events`.
+
+use crate::mml::ast::{Accidental, Duration, OctaveShift, Span, TextKind, Token, TokenKind};
+use crate::mml::dialect::Dialect;
+
+/// Tokenizes one MML part in the Mabinogi dialect. Spans are relative to `source`.
+pub fn lex(source: &str) -> Vec<Token> {
+    lex_at(source, 0)
+}
+
+/// Tokenizes one MML part whose first byte sits at `offset` in a larger string
+pub fn lex_at(source: &str, offset: usize) -> Vec<Token> {
+    lex_with(source, offset, &Dialect::default())
+}
+
+/// Tokenizes one MML part with the token boundaries of `dialect`
+pub fn lex_with(source: &str, offset: usize, dialect: &Dialect) -> Vec<Token> {
+    Lexer::new(source, offset, dialect.exact_lengths, dialect.text_events).run()
+}
+
+/// Raw pieces of one token before interpretation
+struct RawToken {
+    head: char,
+    head_span: Span,
+    accidental: Option<(char, Span)>,
+    /// `%` marking a tick length
+    percent: Option<Span>,
+    digits: Option<(String, Span)>,
+    /// Span of all dots read
+    dot: Option<Span>,
+    dots: u8,
+    tie: Option<Span>,
+}
+
+impl RawToken {
+    /// Span from the head through the last piece, excluding the tie
+    fn span_through(&self, pieces: &[Option<Span>]) -> Span {
+        pieces.iter().flatten().fold(self.head_span, |span, piece| span.to(*piece))
+    }
+
+    fn digit_span(&self) -> Option<Span> {
+        self.digits.as_ref().map(|(_, span)| *span)
+    }
+
+    /// Written length: digits, in ticks after `%`
+    fn length(&self) -> Option<Duration> {
+        self.digits.as_ref().map(|(text, _)| duration(number(text), self.percent.is_some()))
+    }
+
+    /// Span of the length, including `%` when digits follow it
+    fn length_span(&self) -> Option<Span> {
+        self.digit_span().map(|digits| self.percent.map_or(digits, |percent| percent.to(digits)))
+    }
+
+    /// `%` with no digits after it
+    fn bare_percent(&self) -> Option<Span> {
+        self.percent.filter(|_| self.digits.is_none())
+    }
+}
+
+/// What a token head reads from its raw pieces
+struct Reading {
+    kind: TokenKind,
+    span: Span,
+    tie: Option<Span>,
+    /// Pieces the C++ regexes matched but never used
+    skipped: Vec<Option<Span>>,
+}
+
+struct Lexer {
+    // Whitespace is insignificant anywhere, even inside a token
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    offset: usize,
+    tokens: Vec<Token>,
+    in_chord: bool,
+    /// Read `%` tick lengths and any number of dots
+    exact_lengths: bool,
+    /// Read `!"..."` and `~"..."`
+    text_events: bool,
+}
+
+impl Lexer {
+    fn new(source: &str, offset: usize, exact_lengths: bool, text_events: bool) -> Self {
+        Lexer {
+            chars: source.char_indices().filter(|(_, c)| !c.is_whitespace()).collect(),
+            pos: 0,
+            offset,
+            tokens: Vec::new(),
+            in_chord: false,
+            exact_lengths,
+            text_events,
+        }
+    }
+
+    fn run(mut self) -> Vec<Token> {
+        while self.pos < self.chars.len() {
+            let (_, c) = self.chars[self.pos];
+            if matches!(c, '[' | ']' | ':') {
+                self.read_loop_token(c);
+                continue;
+            }
+            if c == '\'' {
+                self.read_chord_quote();
+                continue;
+            }
+            if matches!(c, '{' | '}') {
+                self.read_tuplet_brace(c);
+                continue;
+            }
+            if c.eq_ignore_ascii_case(&'q') {
+                self.read_gate();
+                continue;
+            }
+            if c.eq_ignore_ascii_case(&'k') || c == '_' {
+                self.read_transpose(c == '_');
+                continue;
+            }
+            if self.text_events && matches!(c, '!' | '~' | '"') {
+                self.read_text(c);
+                continue;
+            }
+            if !is_token_head(c) {
+                // Stray characters are skipped, as with regex find_iter
+                let span = self.bump();
+                self.skip(span);
+                continue;
+            }
+
+            let raw = self.read_raw();
+            let reading = interpret(&raw);
+
+            self.tokens.push(Token { kind: reading.kind, span: reading.span });
+            for span in reading.skipped.into_iter().flatten() {
+                self.skip(span);
+            }
+            if let Some(span) = reading.tie {
+                self.tokens.push(Token { kind: TokenKind::Tie, span });
+            }
+        }
+
+        self.tokens
+    }
+
+    /// Records skipped text, merging runs
+    fn skip(&mut self, span: Span) {
+        if let Some(Token { kind: TokenKind::Skipped, span: last }) = self.tokens.last_mut() {
+            *last = last.to(span);
+            return;
+        }
+        self.tokens.push(Token { kind: TokenKind::Skipped, span });
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).map(|&(_, c)| c)
+    }
+
+    fn bump(&mut self) -> Span {
+        let (index, c) = self.chars[self.pos];
+        self.pos += 1;
+        Span::new(self.offset + index, self.offset + index + c.len_utf8())
+    }
+
+    /// `[`, `:` or `]` with an optional repeat count
+    fn read_loop_token(&mut self, c: char) {
+        let mut span = self.bump();
+        let kind = match c {
+            '[' => TokenKind::LoopStart,
+            ':' => TokenKind::LoopBreak,
+            _ => {
+                let mut digits = String::new();
+                while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+                    span = span.to(self.bump());
+                    digits.push(c);
+                }
+                TokenKind::LoopEnd(if digits.is_empty() { None } else { Some(number(&digits)) })
+            }
+        };
+        self.tokens.push(Token { kind, span });
+    }
+
+    /// `q` with an optional `%` and value
+    fn read_gate(&mut self) {
+        let mut span = self.bump();
+        let ticks = self.peek() == Some('%');
+        if ticks {
+            span = span.to(self.bump());
+        }
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            span = span.to(self.bump());
+            digits.push(c);
+        }
+        let value = if digits.is_empty() { None } else { Some(number(&digits)) };
+        self.tokens.push(Token { kind: TokenKind::Gate { value, ticks }, span });
+    }
+
+    /// `k` or `_` with an optional sign and value
+    fn read_transpose(&mut self, relative: bool) {
+        let mut span = self.bump();
+        let mut sign = 1;
+        if let Some(c @ ('+' | '-')) = self.peek() {
+            span = span.to(self.bump());
+            if c == '-' {
+                sign = -1;
+            }
+        }
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            span = span.to(self.bump());
+            digits.push(c);
+        }
+        let semitones = if digits.is_empty() { None } else { Some(sign * number(&digits) as i32) };
+        self.tokens.push(Token { kind: TokenKind::Transpose { semitones, relative }, span });
+    }
+
+    /// `!"marker"` or `~"lyric"` through the closing quote. Whitespace
+    /// between the quotes stays in the span; a quote without `!` or `~`, and
+    /// `!` or `~` without a quote, are skipped.
+    fn read_text(&mut self, c: char) {
+        let mut span = self.bump();
+        if c != '"' {
+            if self.peek() != Some('"') {
+                self.skip(span);
+                return;
+            }
+            span = span.to(self.bump());
+        }
+        let mut closed = false;
+        while let Some(next) = self.peek() {
+            span = span.to(self.bump());
+            if next == '"' {
+                closed = true;
+                break;
+            }
+        }
+        let kind = match c {
+            '!' => TextKind::Marker,
+            '~' => TextKind::Lyric,
+            _ => {
+                self.skip(span);
+                return;
+            }
+        };
+        self.tokens.push(Token { kind: TokenKind::Text { kind, closed }, span });
+    }
+
+    /// Opening `'`, or closing `'` with the chord's length, dots and tie
+    fn read_chord_quote(&mut self) {
+        let span = self.bump();
+        self.in_chord = !self.in_chord;
+        if self.in_chord {
+            self.tokens.push(Token { kind: TokenKind::ChordStart, span });
+            return;
+        }
+
+        let (length, dots, end) = self.read_length(span);
+        self.tokens.push(Token { kind: TokenKind::ChordEnd { length, dots }, span: end });
+        if self.peek() == Some('&') {
+            let span = self.bump();
+            self.tokens.push(Token { kind: TokenKind::Tie, span });
+        }
+    }
+
+    /// `{`, or `}` with the tuplet's length and dots
+    fn read_tuplet_brace(&mut self, c: char) {
+        let span = self.bump();
+        if c == '{' {
+            self.tokens.push(Token { kind: TokenKind::TupletStart, span });
+            return;
+        }
+        let (length, dots, end) = self.read_length(span);
+        self.tokens.push(Token { kind: TokenKind::TupletEnd { length, dots }, span: end });
+    }
+
+    /// Length and dots after a closing `'` or `}`, with the span grown to
+    /// cover them. `%` is read only for exact lengths.
+    fn read_length(&mut self, mut span: Span) -> (Option<Duration>, u8, Span) {
+        let percent = self.exact_lengths && self.peek() == Some('%');
+        if percent {
+            span = span.to(self.bump());
+        }
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            span = span.to(self.bump());
+            digits.push(c);
+        }
+        let (dot, dots) = self.read_dots();
+        if let Some(dot) = dot {
+            span = span.to(dot);
+        }
+        let length = if digits.is_empty() { None } else { Some(duration(number(&digits), percent)) };
+        (length, dots, span)
+    }
+
+    /// One dot, or every consecutive dot for exact lengths
+    fn read_dots(&mut self) -> (Option<Span>, u8) {
+        let mut dot: Option<Span> = None;
+        let mut dots = 0u8;
+        while self.peek() == Some('.') && (dots == 0 || self.exact_lengths) {
+            let span = self.bump();
+            dot = Some(dot.map_or(span, |dot| dot.to(span)));
+            dots = dots.saturating_add(1);
+        }
+        (dot, dots)
+    }
+
+    fn read_raw(&mut self) -> RawToken {
+        let head = self.peek().unwrap();
+        let head_span = self.bump();
+
+        let mut accidental = None;
+        if let Some(c @ ('+' | '-' | '#')) = self.peek() {
+            accidental = Some((c, self.bump()));
+        }
+
+        let percent = if self.exact_lengths && self.peek() == Some('%') { Some(self.bump()) } else { None };
+
+        let mut digits: Option<(String, Span)> = None;
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            let span = self.bump();
+            let (text, digit_span) = digits.get_or_insert_with(|| (String::new(), span));
+            text.push(c);
+            *digit_span = digit_span.to(span);
+        }
+
+        let (dot, dots) = self.read_dots();
+        let tie = if self.peek() == Some('&') { Some(self.bump()) } else { None };
+
+        RawToken { head, head_span, accidental, percent, digits, dot, dots, tie }
+    }
+}
+
+fn is_token_head(c: char) -> bool {
+    matches!(c.to_ascii_lowercase(), 'a'..='g' | 'l' | 'n' | 'o' | 'r' | 't' | 'v' | '<' | '>')
+}
+
+/// Parses digits the way `parse::<i32>().unwrap_or(0)` did
+fn number(digits: &str) -> u32 {
+    digits.parse::<i32>().unwrap_or(0) as u32
+}
+
+fn duration(value: u32, ticks: bool) -> Duration {
+    if ticks {
+        Duration::Ticks(value)
+    } else {
+        Duration::Division(value)
+    }
+}
+
+fn interpret(raw: &RawToken) -> Reading {
+    let head = raw.head.to_ascii_lowercase();
+    let digits = raw.digits.as_ref().map(|(text, _)| number(text));
+    let accidental = raw.accidental.map(|(_, span)| span);
+
+    match head {
+        'a'..='g' => Reading {
+            kind: TokenKind::Note {
+                note: head,
+                accidental: match raw.accidental {
+                    Some(('+', _)) | Some(('#', _)) => Some(Accidental::Sharp),
+                    Some(('-', _)) => Some(Accidental::Flat),
+                    _ => None,
+                },
+                length: raw.length(),
+                dots: raw.dots,
+            },
+            span: raw.span_through(&[accidental, raw.length_span(), raw.dot]),
+            tie: raw.tie,
+            skipped: vec![raw.bare_percent()],
+        },
+        // Same regex as notes, but the accidental and dot are never used
+        'n' => Reading {
+            kind: TokenKind::AbsoluteNote(digits),
+            span: raw.span_through(&[accidental, raw.digit_span()]),
+            tie: raw.tie,
+            skipped: vec![raw.percent, raw.dot],
+        },
+        // `[rR]([0-9]*)(\.?)` stops at an accidental and never reads a tie
+        'r' if raw.accidental.is_some() => Reading {
+            kind: TokenKind::Rest { length: None, dots: 0 },
+            span: raw.head_span,
+            tie: None,
+            skipped: vec![accidental, raw.percent, raw.digit_span(), raw.dot, raw.tie],
+        },
+        'r' => Reading {
+            kind: TokenKind::Rest { length: raw.length(), dots: raw.dots },
+            span: raw.span_through(&[raw.length_span(), raw.dot]),
+            tie: None,
+            skipped: vec![raw.bare_percent(), raw.tie],
+        },
+        'l' if raw.percent.is_some() && raw.accidental.is_none() => Reading {
+            // `l%30` reads its ticks whole; the leading-zero rule is for divisions
+            kind: TokenKind::Length { value: raw.length(), dots: raw.dots },
+            span: raw.span_through(&[raw.length_span(), raw.dot]),
+            tie: raw.tie,
+            skipped: vec![raw.bare_percent()],
+        },
+        _ => interpret_control(raw, head),
+    }
+}
+
+/// `([lotv<>])([1-9][0-9]*|0?)(\.?)(&?)` reads nothing past an accidental,
+/// and only a single digit after a leading zero. A `%` stops the value like
+/// an accidental, except on `l`.
+fn interpret_control(raw: &RawToken, head: char) -> Reading {
+    let mut skipped = Vec::new();
+    let blocker = raw.accidental.map(|(_, span)| span).or(raw.percent);
+    let (value, value_span, rest_read) = match (blocker, &raw.digits) {
+        (Some(span), _) => {
+            skipped.push(Some(span));
+            if raw.accidental.is_some() {
+                skipped.push(raw.percent);
+            }
+            skipped.push(raw.digit_span());
+            (None, None, false)
+        }
+        (None, None) => (None, None, true),
+        (None, Some((text, span))) if text.starts_with('0') => {
+            let zero = Span::new(span.start, span.start + 1);
+            if text.len() > 1 {
+                skipped.push(Some(Span::new(zero.end, span.end)));
+            }
+            (Some(0), Some(zero), text.len() == 1)
+        }
+        (None, Some((text, span))) => (Some(number(text)), Some(*span), true),
+    };
+
+    match head {
+        'l' if rest_read => Reading {
+            kind: TokenKind::Length { value: value.map(Duration::Division), dots: raw.dots },
+            span: raw.span_through(&[value_span, raw.dot]),
+            tie: raw.tie,
+            skipped,
+        },
+        'l' => {
+            skipped.extend([raw.dot, raw.tie]);
+            Reading {
+                kind: TokenKind::Length { value: value.map(Duration::Division), dots: 0 },
+                span: raw.span_through(&[value_span]),
+                tie: None,
+                skipped,
+            }
+        }
+        'o' | 't' | 'v' => {
+            skipped.extend([raw.dot, raw.tie]);
+            let kind = match head {
+                'o' => TokenKind::Octave(value),
+                't' => TokenKind::Tempo(value),
+                _ => TokenKind::Volume(value),
+            };
+            Reading { kind, span: raw.span_through(&[value_span]), tie: None, skipped }
+        }
+        _ => {
+            // `<` and `>` take no value at all
+            skipped.extend([value_span, raw.dot, raw.tie]);
+            let shift = if head == '<' { OctaveShift::Down } else { OctaveShift::Up };
+            Reading { kind: TokenKind::OctaveShift(shift), span: raw.head_span, tie: None, skipped }
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! MML front end: lexer, parser and syntax tree
+//!
+//! `Mf2tt2mf` builds its MIDI events from the tree produced here, so the
+//! nodes and spans are exactly what the converter understood.
+
+pub mod ast;
+pub mod diagnostic;
+pub mod dialect;
+pub mod lexer;
+pub mod parser;
+
+pub use diagnostic::{Diagnostic, DiagnosticCode, Severity};
+pub use dialect::Dialect;
+pub use lexer::{lex, lex_with};
+pub use parser::{parse, parse_track, parse_track_with, parse_with};
@@ -0,0 +1,365 @@
+//! MML parser building a per-track syntax tree
+
+use crate::constants::mml::MAX_EXPANDED_NODES;
+use crate::mml::ast::{expanded_len, Node, NodeKind, Score, Span, Token, TokenKind, Track};
+use crate::mml::diagnostic::{Diagnostic, DiagnosticCode};
+use crate::mml::dialect::Dialect;
+use crate::mml::lexer::lex_with;
+
+const MML_PREFIX: &str = "MML@";
+
+/// Parses a full `MML@a,b,c;` string in the Mabinogi dialect
+pub fn parse(mml: &str) -> Result<Score, Vec<Diagnostic>> {
+    parse_with(mml, &Dialect::default())
+}
+
+/// Parses a full `MML@...;` string with any number of parts up to the
+/// dialect's limit
+///
+/// Text before `MML@` and after the closing `;` is ignored. On failure every
+/// problem found is returned, ordered by offset.
+pub fn parse_with(mml: &str, dialect: &Dialect) -> Result<Score, Vec<Diagnostic>> {
+    let start = match mml.find(MML_PREFIX) {
+        Some(start) => start,
+        None => {
+            let text: String = mml.trim_start().chars().take(MML_PREFIX.len()).collect();
+            let offset = mml.len() - mml.trim_start().len();
+            return Err(vec![Diagnostic::new(
+                DiagnosticCode::MissingPrefix,
+                None,
+                offset,
+                &text,
+                format!("expected '{}' at the start of the MML", MML_PREFIX),
+            )]);
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut parts: Vec<Span> = Vec::new();
+    let mut part_start = start + MML_PREFIX.len();
+    let mut unknown: Option<Span> = None;
+    let mut end = None;
+    // Opening quote of the `"..."` being read; any character in it, even ','
+    // and ';', is text
+    let mut open_quote: Option<usize> = None;
+
+    for (index, c) in mml[part_start..].char_indices() {
+        let index = index + start + MML_PREFIX.len();
+        if dialect.text_events && c == '"' {
+            open_quote = if open_quote.is_some() { None } else { Some(index) };
+        }
+        let in_text = open_quote.is_some();
+        if in_text || is_part_char(c, dialect) || c == ',' || c == ';' {
+            flush_unknown(mml, &mut unknown, parts.len(), &mut diagnostics);
+        } else {
+            let span = Span::new(index, index + c.len_utf8());
+            unknown = Some(unknown.map_or(span, |run| run.to(span)));
+            continue;
+        }
+        if in_text {
+            continue;
+        }
+
+        if c == ',' || c == ';' {
+            parts.push(Span::new(part_start, index));
+            part_start = index + 1;
+            if c == ';' {
+                end = Some(index + 1);
+                break;
+            }
+        }
+    }
+
+    let end = match end {
+        Some(end) => end,
+        None => {
+            flush_unknown(mml, &mut unknown, parts.len(), &mut diagnostics);
+            parts.push(Span::new(part_start, mml.len()));
+            diagnostics.push(match open_quote {
+                // The quote swallowed the ';'
+                Some(quote) => Diagnostic::new(
+                    DiagnosticCode::UnterminatedText,
+                    Some(parts.len() - 1),
+                    quote,
+                    &mml[quote..],
+                    "text without a closing '\"'".to_string(),
+                ),
+                None => Diagnostic::new(
+                    DiagnosticCode::MissingTerminator,
+                    Some(parts.len() - 1),
+                    mml.len(),
+                    "",
+                    "expected ';' at the end of the MML".to_string(),
+                ),
+            });
+            mml.len()
+        }
+    };
+
+    if parts.len() > dialect.max_parts {
+        // Point at the first surplus ',', or the end with no parts allowed
+        let offset = parts[dialect.max_parts.max(1) - 1].end;
+        diagnostics.push(Diagnostic::new(
+            DiagnosticCode::WrongPartCount,
+            None,
+            offset,
+            &mml[offset..(offset + 1).min(mml.len())],
+            format!("expected at most {} comma-separated parts, found {}", dialect.max_parts, parts.len()),
+        ));
+    }
+
+    let mut tracks = Vec::with_capacity(parts.len());
+    if diagnostics.is_empty() {
+        for (index, span) in parts.iter().enumerate() {
+            // Leading whitespace is not part of the track
+            let text = span.text(mml).trim_start();
+            match parse_track_with(text, span.end - text.len(), dialect) {
+                Ok(track) => tracks.push(track),
+                Err(errors) => diagnostics.extend(errors.into_iter().map(|d| Diagnostic { track: Some(index), ..d })),
+            }
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| d.offset);
+        return Err(diagnostics);
+    }
+
+    Ok(Score { tracks, span: Span::new(start, end) })
+}
+
+/// Characters allowed inside a part
+fn is_part_char(c: char, dialect: &Dialect) -> bool {
+    c.is_whitespace()
+        || c.is_ascii_digit()
+        || matches!(c.to_ascii_lowercase(), 'a'..='g' | 'l' | 'n' | 'o' | 'r' | 't' | 'v')
+        || matches!(c, '#' | '<' | '>' | '.' | '&' | '+' | '-')
+        || (dialect.loops && matches!(c, '[' | ']' | ':'))
+        || (dialect.chords && c == '\'')
+        || (dialect.gate_time && matches!(c, 'q' | 'Q' | '%'))
+        || (dialect.exact_lengths && matches!(c, '{' | '}' | '%'))
+        || (dialect.transpose && matches!(c, 'k' | 'K' | '_'))
+        || (dialect.text_events && matches!(c, '!' | '~' | '"'))
+}
+
+fn flush_unknown(mml: &str, unknown: &mut Option<Span>, track: usize, diagnostics: &mut Vec<Diagnostic>) {
+    if let Some(span) = unknown.take() {
+        diagnostics.push(Diagnostic::new(
+            DiagnosticCode::UnknownCharacter,
+            Some(track),
+            span.start,
+            span.text(mml),
+            format!("unknown character '{}'", span.text(mml)),
+        ));
+    }
+}
+
+/// Parses a single part in the Mabinogi dialect. Spans are relative to `source`.
+///
+/// Fails on unbalanced loop brackets, chord quotes or tuplet braces, text
+/// without a closing quote, or loops that unroll past `MAX_EXPANDED_NODES`. The diagnostics carry no track index.
+pub fn parse_track(source: &str) -> Result<Track, Vec<Diagnostic>> {
+    parse_track_at(source, 0)
+}
+
+/// Parses a single part whose first byte sits at `offset` in a larger string
+pub fn parse_track_at(source: &str, offset: usize) -> Result<Track, Vec<Diagnostic>> {
+    parse_track_with(source, offset, &Dialect::default())
+}
+
+/// Parses a single part with the token boundaries of `dialect`
+///
+/// Characters are not checked against the dialect here; `parse_with` does
+/// that for whole MML strings.
+pub fn parse_track_with(source: &str, offset: usize, dialect: &Dialect) -> Result<Track, Vec<Diagnostic>> {
+    let mut track = Track {
+        nodes: Vec::new(),
+        span: Span::new(offset, offset + source.len()),
+        skipped: Vec::new(),
+    };
+    let mut errors = Vec::new();
+    build_nodes(&mut track, lex_with(source, offset, dialect), source, offset, &mut errors);
+
+    if errors.is_empty() && expanded_len(&track.nodes) > MAX_EXPANDED_NODES {
+        errors.push(Diagnostic::new(
+            DiagnosticCode::LoopTooLong,
+            None,
+            offset,
+            source,
+            format!("loops expand to more than {} notes and commands", MAX_EXPANDED_NODES),
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(track)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Kind of an open `[` or `{`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Group {
+    Loop,
+    Tuplet,
+}
+
+fn build_nodes(track: &mut Track, tokens: Vec<Token>, source: &str, offset: usize, errors: &mut Vec<Diagnostic>) {
+    let text = |span: Span| &source[span.start - offset..span.end - offset];
+    let error = |code: DiagnosticCode, span: Span, message: String| {
+        Diagnostic::new(code, None, span.start, text(span), message)
+    };
+    let mut nodes: Vec<Node> = Vec::new();
+    // Node lists of the enclosing loops and tuplets, with the span of their
+    // opening bracket
+    let mut open: Vec<(Vec<Node>, Span, Group)> = Vec::new();
+    // Notes of the chord being read, with the span of its opening `'`
+    let mut chord: Option<(Vec<Node>, Span)> = None;
+
+    for token in tokens {
+        if chord.is_some()
+            && !matches!(
+                token.kind,
+                TokenKind::Note { .. }
+                    | TokenKind::AbsoluteNote(_)
+                    | TokenKind::OctaveShift(_)
+                    | TokenKind::Tie
+                    | TokenKind::Skipped
+                    | TokenKind::ChordEnd { .. }
+            )
+        {
+            errors.push(error(DiagnosticCode::MalformedChord, token.span,
+                format!("'{}' cannot appear inside a chord", text(token.span))));
+            continue;
+        }
+
+        let in_tuplet = open.iter().any(|(_, _, group)| *group == Group::Tuplet);
+        let kind = match token.kind {
+            TokenKind::Skipped => {
+                track.skipped.push(token.span);
+                continue;
+            }
+            TokenKind::Tie if chord.is_some() => {
+                // Chord notes sound for the chord length; only `'ceg'&` ties
+                track.skipped.push(token.span);
+                continue;
+            }
+            TokenKind::Tie => {
+                // The lexer only emits ties that follow a note, `l` or chord
+                if let Some(node) = nodes.last_mut() {
+                    if let NodeKind::Note { tied, .. }
+                    | NodeKind::AbsoluteNote { tied, .. }
+                    | NodeKind::Length { tied, .. }
+                    | NodeKind::Chord { tied, .. } = &mut node.kind
+                    {
+                        *tied = true;
+                        node.span = node.span.to(token.span);
+                    }
+                }
+                continue;
+            }
+            TokenKind::LoopStart | TokenKind::LoopBreak if in_tuplet => {
+                errors.push(error(DiagnosticCode::MalformedTuplet, token.span,
+                    format!("'{}' cannot appear inside a tuplet", text(token.span))));
+                continue;
+            }
+            TokenKind::LoopStart => {
+                open.push((std::mem::take(&mut nodes), token.span, Group::Loop));
+                continue;
+            }
+            TokenKind::LoopEnd(count) => {
+                match open.last() {
+                    Some((_, _, Group::Loop)) => {
+                        let (outer, start, _) = open.pop().unwrap();
+                        let body = std::mem::replace(&mut nodes, outer);
+                        nodes.push(Node { kind: NodeKind::Loop { body, count }, span: start.to(token.span) });
+                    }
+                    _ => errors.push(error(DiagnosticCode::UnbalancedLoop, token.span,
+                        "']' without a matching '['".to_string())),
+                }
+                continue;
+            }
+            TokenKind::LoopBreak if open.is_empty() => {
+                track.skipped.push(token.span);
+                continue;
+            }
+            TokenKind::LoopBreak => NodeKind::LoopBreak,
+            TokenKind::TupletStart => {
+                open.push((std::mem::take(&mut nodes), token.span, Group::Tuplet));
+                continue;
+            }
+            TokenKind::TupletEnd { length, dots } => {
+                match open.last() {
+                    Some((_, _, Group::Tuplet)) => {
+                        let (outer, start, _) = open.pop().unwrap();
+                        let body = std::mem::replace(&mut nodes, outer);
+                        let span = start.to(token.span);
+                        if body.iter().any(|node| node.kind.takes_time()) {
+                            nodes.push(Node { kind: NodeKind::Tuplet { body, length, dots }, span });
+                        } else {
+                            errors.push(error(DiagnosticCode::MalformedTuplet, span, "tuplet without notes".to_string()));
+                        }
+                    }
+                    _ => errors.push(error(DiagnosticCode::MalformedTuplet, token.span,
+                        "'}' without a matching '{'".to_string())),
+                }
+                continue;
+            }
+            TokenKind::ChordStart => {
+                chord = Some((Vec::new(), token.span));
+                continue;
+            }
+            TokenKind::ChordEnd { length, dots } => {
+                // The lexer pairs quotes, so a chord is always open here
+                let Some((notes, start)) = chord.take() else { continue };
+                let span = start.to(token.span);
+                if !notes.iter().any(|note| matches!(note.kind, NodeKind::Note { .. } | NodeKind::AbsoluteNote { .. })) {
+                    errors.push(error(DiagnosticCode::MalformedChord, span, "chord without notes".to_string()));
+                    continue;
+                }
+                nodes.push(Node { kind: NodeKind::Chord { notes, length, dots, tied: false }, span });
+                continue;
+            }
+            TokenKind::Note { note, accidental, length, dots } => {
+                NodeKind::Note { note, accidental, length, dots, tied: false }
+            }
+            TokenKind::Rest { length, dots } => NodeKind::Rest { length, dots },
+            TokenKind::Length { value, dots } => NodeKind::Length { value, dots, tied: false },
+            TokenKind::Octave(value) => NodeKind::Octave(value),
+            TokenKind::OctaveShift(shift) => NodeKind::OctaveShift(shift),
+            TokenKind::Tempo(value) => NodeKind::Tempo(value),
+            TokenKind::Volume(value) => NodeKind::Volume(value),
+            TokenKind::AbsoluteNote(value) => NodeKind::AbsoluteNote { value, tied: false },
+            TokenKind::Gate { value, ticks } => NodeKind::Gate { value, ticks },
+            TokenKind::Transpose { semitones, relative } => NodeKind::Transpose { semitones, relative },
+            TokenKind::Text { closed: false, .. } => {
+                errors.push(error(DiagnosticCode::UnterminatedText, token.span,
+                    "text without a closing '\"'".to_string()));
+                continue;
+            }
+            TokenKind::Text { kind, closed: true } => {
+                // Between the opening quote and the closing one
+                let written = text(token.span);
+                let body = &written[written.find('"').map_or(0, |quote| quote + 1)..written.len() - 1];
+                NodeKind::Text { kind, text: body.to_string() }
+            }
+        };
+        let node = Node { kind, span: token.span };
+        match chord.as_mut() {
+            Some((notes, _)) => notes.push(node),
+            None => nodes.push(node),
+        }
+    }
+
+    if let Some((_, start)) = chord {
+        errors.push(error(DiagnosticCode::MalformedChord, start, "chord without a closing quote".to_string()));
+    }
+    for (_, start, group) in open.iter().rev() {
+        errors.push(match group {
+            Group::Loop => error(DiagnosticCode::UnbalancedLoop, *start, "'[' without a matching ']'".to_string()),
+            Group::Tuplet => error(DiagnosticCode::MalformedTuplet, *start, "'{' without a matching '}'".to_string()),
+        });
+    }
+    errors.sort_by_key(|d| d.offset);
+    track.nodes = nodes;
+}
@@ -0,0 +1,230 @@
+//! Shortens MML without changing the MIDI it converts to
+//!
+//! Each part is spelled again from the notes `Mf2tt2mf` builds for it,
+//! choosing the `l` length, octave commands and volumes that take the fewest
+//! characters. A spelling is kept only when its track comes out byte for
+//! byte the same; otherwise the part just loses its whitespace, or stays as
+//! written.
+
+use crate::constants::{event_timing, meta, mml};
+use crate::mf2tt2mf::Mf2tt2mf;
+use crate::midi_to_mml::{self, Lengths, Token};
+use crate::mml::ast::TokenKind;
+use crate::mml::{lex_with, parse_with, Diagnostic, Dialect};
+use crate::options::ConversionOptions;
+use crate::voices::Note;
+
+/// Events of one track as `(tick, bytes)`, compared to prove two spellings
+/// play alike
+type TrackBytes = Vec<(u32, Vec<u8>)>;
+
+/// Characters in one part before and after minifying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartLength {
+    pub before: usize,
+    pub after: usize,
+    pub limit: usize,
+}
+
+impl PartLength {
+    /// `true` when the minified part is within the limit
+    pub fn fits(&self) -> bool {
+        self.after <= self.limit
+    }
+}
+
+/// Minified MML and the length of each of its parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minified {
+    pub mml: String,
+    pub parts: Vec<PartLength>,
+}
+
+impl Minified {
+    /// `true` when every part is within the limit
+    pub fn fits(&self) -> bool {
+        self.parts.iter().all(PartLength::fits)
+    }
+}
+
+/// Rewrites MML to the shortest string that converts to identical MIDI
+///
+/// # Examples
+///
+/// ```
+/// use yks_converter::optimize::Optimizer;
+///
+/// let minified = Optimizer::new().minify("MML@o4 c8 d8 e8 o5 c8 v8 r4,,;").unwrap();
+/// assert_eq!(minified.mml, "MML@l8cde>cl2,,;");
+/// assert!(minified.fits());
+/// ```
+pub struct Optimizer {
+    dialect: Dialect,
+    limit: usize,
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Optimizer {
+            dialect: Dialect::default(),
+            limit: mml::PART_CHARACTER_LIMIT,
+        }
+    }
+
+    /// Syntax the MML is read with, Mabinogi's by default
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    /// Characters allowed in each part, which [`Minified::parts`] is
+    /// reported against
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Minifies `mml`, or returns why it does not parse
+    pub fn minify(&self, mml: &str) -> Result<Minified, Vec<Diagnostic>> {
+        let score = parse_with(mml, &self.dialect)?;
+        let written: Vec<String> = score.tracks.iter().map(|track| track.span.text(mml).to_string()).collect();
+        let original = self.build(mml).unwrap_or_default();
+
+        let mut parts = written.clone();
+        for (i, track) in original.iter().enumerate().take(parts.len()) {
+            let mut candidates: Vec<String> = self.respell(track).into_iter().collect();
+            candidates.push(self.compact(&written[i]));
+            candidates.sort_by_key(|candidate| candidate.chars().count());
+
+            for candidate in candidates {
+                if candidate.chars().count() >= parts[i].chars().count() {
+                    break;
+                }
+                let mut trial = parts.clone();
+                trial[i] = candidate;
+                if self.build(&join(&trial)).is_some_and(|tracks| tracks.get(i) == Some(track)) {
+                    parts = trial;
+                    break;
+                }
+            }
+        }
+
+        let lengths = written.iter().zip(&parts)
+            .map(|(before, after)| PartLength {
+                before: before.chars().count(),
+                after: after.chars().count(),
+                limit: self.limit,
+            })
+            .collect();
+        Ok(Minified { mml: join(&parts), parts: lengths })
+    }
+
+    /// Events of every part of `mml`, or `None` when it does not parse
+    fn build(&self, mml: &str) -> Option<Vec<TrackBytes>> {
+        let mut parser = Mf2tt2mf::new(1, 1, 64, 0);
+        parser.set_options(ConversionOptions { dialect: self.dialect.clone(), ..Default::default() });
+        parser.from_mml(mml).ok()?;
+        Some(parser.build().iter()
+            .map(|track| track.iter().map(|event| (event.lead_time(), event.to_buffer().as_slice().to_vec())).collect())
+            .collect())
+    }
+
+    /// `part` without whitespace or anything the converter skips
+    fn compact(&self, part: &str) -> String {
+        lex_with(part, 0, &self.dialect).iter()
+            .filter(|token| token.kind != TokenKind::Skipped)
+            .map(|token| token.span.text(part))
+            .collect()
+    }
+
+    /// The shortest spelling of the notes, tempo changes and end of `track`,
+    /// or `None` when it holds no notes or plays two at once
+    fn respell(&self, track: &TrackBytes) -> Option<String> {
+        let lead_in = event_timing::TRACK_START_TIME;
+        let mut notes: Vec<Note> = Vec::new();
+        let mut tempos: Vec<(u32, u32)> = Vec::new();
+        let mut end = None;
+        for (time, data) in track {
+            match data.as_slice() {
+                [0x90..=0x9f, pitch, velocity] if *velocity > 0 => {
+                    notes.push(Note { start: time.checked_sub(lead_in)?, end: u32::MAX, pitch: *pitch, velocity: *velocity });
+                }
+                [0x80..=0x9f, pitch, _] => {
+                    let note = notes.iter_mut().rev().find(|note| note.pitch == *pitch && note.end == u32::MAX)?;
+                    note.end = time.checked_sub(lead_in)?;
+                }
+                [0xff, meta::TEMPO, 3, tempo @ ..] if *time >= lead_in => {
+                    let microseconds = u32::from_be_bytes([0, tempo[0], tempo[1], tempo[2]]);
+                    let bpm = (60_000_000 / microseconds.max(1)..).take(2).find(|bpm| 60_000_000 / bpm.max(&1) == microseconds)?;
+                    tempos.push((time - lead_in, bpm));
+                }
+                [0xff, meta::END_OF_TRACK, ..] => end = time.checked_sub(lead_in),
+                _ => {}
+            }
+        }
+        if notes.is_empty() || notes.windows(2).any(|pair| pair[1].start < pair[0].end) {
+            return None;
+        }
+
+        // The track ends one `l` length after its last note or rest, so the
+        // `l` is chosen together with the closing rest and any `l` after it
+        let tokens = midi_to_mml::tokens(&notes, &tempos);
+        let played: u32 = tokens.iter()
+            .map(|token| match *token {
+                Token::Rest(ticks) | Token::Note { length: ticks, .. } => ticks,
+                Token::Tempo(_) => 0,
+            })
+            .sum();
+        let remaining = end?.checked_sub(played)?;
+        let mut lengths = Lengths::new();
+        let default = lengths.best_default(&tokens, |lengths, default| {
+            ending(lengths, remaining, default).map_or(usize::MAX / 2, |(spent, ..)| spent)
+        });
+        let (_, rest, last) = ending(&mut lengths, remaining, default)?;
+
+        let mut tokens = tokens;
+        if rest > 0 {
+            tokens.push(Token::Rest(rest));
+        }
+        let mut part = midi_to_mml::render(&tokens, default, &mut lengths);
+        if let Some(last) = last {
+            part.push('l');
+            part.push_str(lengths.text(last));
+        }
+        Some(part)
+    }
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The cheapest way to fill the `remaining` ticks before the end of a part
+/// written with `default` as `l`: the characters spent, the closing rest
+/// and the `l` length to switch to after it, if any
+fn ending(lengths: &mut Lengths, remaining: u32, default: u32) -> Option<(usize, u32, Option<u32>)> {
+    // Keeping `l` leaves a rest of any length but a single tick
+    let kept = remaining.checked_sub(default)
+        .filter(|&rest| rest != 1)
+        .map(|rest| (lengths.cost(Token::Rest(rest), default), rest, None));
+
+    // Switching `l` at the end is only worth it with at most one rest
+    let switched = lengths.ticks()
+        .filter(|&last| last != default && last <= remaining)
+        .filter_map(|last| {
+            let rest = remaining - last;
+            let rest_spent = match rest {
+                0 => 0,
+                rest if rest == default => 1,
+                rest => 1 + lengths.written(rest)?.len(),
+            };
+            Some((rest_spent + 1 + lengths.text(last).len(), rest, Some(last)))
+        })
+        .min();
+
+    kept.into_iter().chain(switched).min_by_key(|&(spent, _, last)| (spent, last.is_some()))
+}
+
+fn join(parts: &[String]) -> String {
+    format!("MML@{};", parts.join(","))
+}
@@ -0,0 +1,100 @@
+//! Options controlling MML to MIDI conversion
+
+use crate::constants::{event_timing, sysex};
+use crate::mml::dialect::Dialect;
+
+/// Settings accepted by `YksConverter`
+///
+/// The default reproduces the original converter byte for byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionOptions {
+    /// Fail instead of clamping, skipping or coercing tokens
+    ///
+    /// Every warning `to_buffer_with_report` would list becomes a
+    /// `ConversionError::StrictModeViolation`.
+    pub strict: bool,
+    /// MML syntax to accept
+    pub dialect: Dialect,
+    /// Semitones added to every note, on top of any `k`/`_` in the MML
+    ///
+    /// Clamped to ±96. Transposed notes still wrap by octaves into the
+    /// playable range.
+    pub transpose: i32,
+    /// Ticks of silence before the first note, 384 (one bar of 4/4)
+    ///
+    /// Counted at 96 ticks per quarter note and scaled to the timebase. The
+    /// program and controllers keep their ticks (192-197), or move to the
+    /// first note when the lead-in ends before them.
+    pub lead_in: u32,
+    /// Send the Roland GS reset SysEx at the start of the file
+    pub gs_reset: bool,
+    /// Text meta event (FF 01) at the start of the file, the Yokoso Project
+    /// credit by default; `None` leaves it out
+    pub text: Option<String>,
+    /// Copyright meta event (FF 02) at the start of the file; `None` leaves
+    /// it out
+    pub copyright: Option<String>,
+    /// Time signature meta event (FF 58), such as `(3, 4)`; `None` leaves it
+    /// out
+    pub time_signature: Option<(u8, u8)>,
+    /// Key signature meta event (FF 59): sharps (negative for flats) and
+    /// whether the key is minor; `None` leaves it out
+    pub key_signature: Option<(i8, bool)>,
+    /// Name every track (FF 03) after its performer and part, even without
+    /// `YksConverter::set_name`
+    pub track_names: bool,
+    /// How the tracks are laid out in the file
+    pub format: OutputFormat,
+    /// Which tempo changes reach the conductor track when parts disagree;
+    /// unused with `OutputFormat::Parts`
+    pub tempo_policy: TempoPolicy,
+}
+
+/// Track layout of the Standard MIDI File
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Format 1 with one track per part, as the original converter writes:
+    /// the first track opens the file and every part keeps its own tempo
+    /// changes
+    #[default]
+    Parts,
+    /// Format 1 with a conductor track first, holding the file header, the
+    /// tempo changes and the markers of every part
+    Conductor,
+    /// Format 0: every part merged into one track
+    SingleTrack,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        ConversionOptions {
+            strict: false,
+            dialect: Dialect::default(),
+            transpose: 0,
+            lead_in: event_timing::TRACK_START_TIME,
+            gs_reset: true,
+            text: Some(sysex::YOKOSO_META_TEXT.to_string()),
+            copyright: None,
+            time_signature: None,
+            key_signature: None,
+            track_names: false,
+            format: OutputFormat::default(),
+            tempo_policy: TempoPolicy::default(),
+        }
+    }
+}
+
+/// How tempo changes from several parts are combined into one tempo map
+///
+/// Either way, at most one change is kept per tick, and a change at tick 0
+/// replaces the default 120 BPM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TempoPolicy {
+    /// Every `t` in every part applies from its tick on, as the game plays
+    /// them; at the same tick the later part wins
+    #[default]
+    Latest,
+    /// Only the first part with a `t` sets the tempo; `t` in other parts is
+    /// dropped
+    FirstPart,
+}
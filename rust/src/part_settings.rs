@@ -0,0 +1,91 @@
+//! Instrument and mixer settings for a performer or a single part
+
+use crate::instruments::Instrument;
+use crate::percussion::DrumMap;
+use std::ops::RangeInclusive;
+
+/// Instrument and channel controllers sent before the first note
+///
+/// The default matches the original converter: the converter's instrument,
+/// centre pan, no reverb, no chorus, expression or volume message, and a
+/// melodic channel. Programs and controller values above 127 are clamped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartSettings {
+    /// GM program; `None` keeps the instrument given to the converter
+    pub instrument: Option<u8>,
+    /// Pan (CC10), 0 is hard left and 127 hard right
+    pub pan: u8,
+    /// Reverb send (CC91)
+    pub reverb: u8,
+    /// Chorus send (CC93), not sent when `None`
+    pub chorus: Option<u8>,
+    /// Expression (CC11), not sent when `None`
+    pub expression: Option<u8>,
+    /// Main volume (CC7), not sent when `None`
+    pub volume: Option<u8>,
+    /// Play on the percussion channel with pitches mapped to drums; no
+    /// program is sent
+    pub percussion: Option<DrumMap>,
+    /// MIDI notes the instrument plays; notes outside it are reported
+    pub range: Option<RangeInclusive<u8>>,
+}
+
+impl Default for PartSettings {
+    fn default() -> Self {
+        PartSettings {
+            instrument: None,
+            pan: 64,
+            reverb: 0,
+            chorus: None,
+            expression: None,
+            volume: None,
+            percussion: None,
+            range: None,
+        }
+    }
+}
+
+impl PartSettings {
+    /// Default settings for a drum part using the GM kit
+    pub fn percussion() -> PartSettings {
+        PartSettings {
+            percussion: Some(DrumMap::default()),
+            ..PartSettings::default()
+        }
+    }
+
+    /// These settings playing `instrument`, with its note range
+    ///
+    /// The drum kit turns on percussion with the GM kit instead, unless a
+    /// drum map is already set.
+    pub fn with_instrument(&self, instrument: Instrument) -> PartSettings {
+        if instrument.is_percussion() {
+            return PartSettings {
+                instrument: Some(instrument.program()),
+                percussion: Some(self.percussion.clone().unwrap_or_default()),
+                range: None,
+                ..self.clone()
+            };
+        }
+        PartSettings {
+            instrument: Some(instrument.program()),
+            range: Some(instrument.range()),
+            percussion: None,
+            ..self.clone()
+        }
+    }
+
+    /// These settings with `instrument` filled in when not set
+    pub fn or_instrument(&self, instrument: u8) -> PartSettings {
+        PartSettings {
+            instrument: Some(self.instrument.unwrap_or(instrument)),
+            ..self.clone()
+        }
+    }
+}
+
+impl From<Instrument> for PartSettings {
+    fn from(instrument: Instrument) -> Self {
+        PartSettings::default().with_instrument(instrument)
+    }
+}
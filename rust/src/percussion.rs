@@ -0,0 +1,62 @@
+//! Drum-kit parts played on the General MIDI percussion channel
+
+use std::collections::BTreeMap;
+
+/// GM drum notes for `c` through `b`, used by [`DrumMap::default`]
+///
+/// The kit pieces General MIDI lays out from note 36 up, with the crash
+/// cymbal on `b` so every octave holds a full kit.
+pub const GM_KIT: [u8; 12] = [
+    36, // c:  Bass Drum 1
+    37, // c+: Side Stick
+    38, // d:  Acoustic Snare
+    39, // d+: Hand Clap
+    40, // e:  Electric Snare
+    41, // f:  Low Floor Tom
+    42, // f+: Closed Hi-Hat
+    43, // g:  High Floor Tom
+    44, // g+: Pedal Hi-Hat
+    45, // a:  Low Tom
+    46, // a+: Open Hi-Hat
+    49, // b:  Crash Cymbal 1
+];
+
+/// Maps the pitches of a drum part to GM drum notes
+///
+/// Pitches are looked up by MIDI note first, then by note letter in any
+/// octave, so `c` plays the bass drum whatever the octave unless one octave
+/// is mapped on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrumMap {
+    pitch_classes: [u8; 12],
+    notes: BTreeMap<u8, u8>,
+}
+
+impl Default for DrumMap {
+    fn default() -> Self {
+        DrumMap {
+            pitch_classes: GM_KIT,
+            notes: BTreeMap::new(),
+        }
+    }
+}
+
+impl DrumMap {
+    /// Plays `drum` for note letter `pitch_class` (0 is `c`, 11 is `b`)
+    pub fn set_pitch_class(&mut self, pitch_class: u8, drum: u8) {
+        self.pitch_classes[(pitch_class % 12) as usize] = drum.min(127);
+    }
+
+    /// Plays `drum` for MIDI note `note` only, ahead of its note letter
+    pub fn set_note(&mut self, note: u8, drum: u8) {
+        self.notes.insert(note, drum.min(127));
+    }
+
+    /// Drum note sounded for MIDI note `note`
+    pub fn drum(&self, note: u8) -> u8 {
+        self.notes
+            .get(&note)
+            .copied()
+            .unwrap_or(self.pitch_classes[(note % 12) as usize])
+    }
+}
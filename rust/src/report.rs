@@ -0,0 +1,35 @@
+//! Conversion report for tokens that did not play as written
+
+use crate::mml::diagnostic::Diagnostic;
+use std::fmt;
+
+/// A warning tied to the MML string it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionWarning {
+    /// Index of the MML string
+    pub index: usize,
+    pub diagnostic: Diagnostic,
+}
+
+impl fmt::Display for ConversionWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MML {}, {}", self.index + 1, self.diagnostic)
+    }
+}
+
+/// Everything the converter clamped, ignored or coerced
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConversionReport {
+    pub warnings: Vec<ConversionWarning>,
+}
+
+impl ConversionReport {
+    pub fn new() -> Self {
+        ConversionReport { warnings: Vec::new() }
+    }
+
+    /// `true` when the MIDI matches the MML exactly as written
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
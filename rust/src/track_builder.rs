@@ -26,7 +26,7 @@ impl TrackBuilder {
 
     pub fn build(&self) -> Vec<String> {
         let mut result = Vec::new();
-        result.push(format!("MTrk"));
+        result.push("MTrk".to_string());
         
         for event in &self.events {
             result.push(format!("{} {}", event.lead_time(), event.value()));
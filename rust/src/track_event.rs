@@ -6,6 +6,21 @@ pub trait TrackEvent {
     fn value(&self) -> String;
     fn to_buffer(&self) -> ByteBuffer;
     fn clone_event(&self) -> Box<dyn TrackEvent>;
+
+    /// First byte of the event: 0x80-0xEF for channel messages, 0xF0 for
+    /// SysEx and 0xFF for meta events
+    ///
+    /// Serializes the whole event to read it, as does `meta_type`; sorts
+    /// should cache the result rather than call it from a comparator.
+    fn status(&self) -> u8 {
+        self.to_buffer().get_at(0)
+    }
+
+    /// Type byte of a meta event, such as 0x51 for a tempo change
+    fn meta_type(&self) -> Option<u8> {
+        let buffer = self.to_buffer();
+        (buffer.get_at(0) == 0xFF && buffer.size() > 1).then(|| buffer.get_at(1))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +56,43 @@ impl TrackEvent for SeqSpec {
     }
 }
 
+/// Bytes of an event with no type of its own, such as pitch bend or an
+/// unknown meta event read from a file, written back unchanged
+#[derive(Debug, Clone)]
+pub struct RawEvent {
+    time: u32,
+    bytes: Vec<u8>,
+}
+
+impl RawEvent {
+    /// `bytes` from the status byte on
+    pub fn new(bytes: Vec<u8>) -> Self {
+        RawEvent { time: 0, bytes }
+    }
+}
+
+impl TrackEvent for RawEvent {
+    fn lead_time(&self) -> u32 { self.time }
+    fn set_lead_time(&mut self, time: u32) { self.time = time; }
+
+    fn value(&self) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("Raw: {}", bytes.join(" "))
+    }
+
+    fn to_buffer(&self) -> ByteBuffer {
+        let mut buffer = ByteBuffer::new();
+        buffer.put_bytes_array(&self.bytes);
+        buffer
+    }
+
+    fn clone_event(&self) -> Box<dyn TrackEvent> {
+        let mut clone = Box::new(RawEvent::new(self.bytes.clone()));
+        clone.set_lead_time(self.time);
+        clone
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MetaText {
     time: u32,
@@ -65,7 +117,7 @@ impl TrackEvent for MetaText {
         let mut buffer = ByteBuffer::new();
         buffer.put_byte(0xFF);
         buffer.put_byte(0x01);
-        buffer.put_byte(self.text.len() as u8);
+        buffer.put_var_len(self.text.len() as u32);
         buffer.put_string(&self.text);
         buffer
     }
@@ -77,6 +129,154 @@ impl TrackEvent for MetaText {
     }
 }
 
+/// Declares a meta event carrying text, such as FF 03 track names
+macro_rules! text_meta_event {
+    ($(#[$doc:meta])* $name:ident, $kind:expr, $label:expr) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone)]
+        pub struct $name {
+            time: u32,
+            text: String,
+        }
+
+        impl $name {
+            pub fn new(text: String) -> Self {
+                $name { time: 0, text }
+            }
+        }
+
+        impl TrackEvent for $name {
+            fn lead_time(&self) -> u32 { self.time }
+            fn set_lead_time(&mut self, time: u32) { self.time = time; }
+
+            fn value(&self) -> String {
+                format!("{}: {}", $label, self.text)
+            }
+
+            fn to_buffer(&self) -> ByteBuffer {
+                let mut buffer = ByteBuffer::new();
+                buffer.put_byte(0xFF);
+                buffer.put_byte($kind);
+                buffer.put_var_len(self.text.len() as u32);
+                buffer.put_string(&self.text);
+                buffer
+            }
+
+            fn clone_event(&self) -> Box<dyn TrackEvent> {
+                let mut clone = Box::new($name::new(self.text.clone()));
+                clone.set_lead_time(self.time);
+                clone
+            }
+        }
+    };
+}
+
+text_meta_event!(
+    /// FF 02 copyright notice
+    Copyright, 0x02, "Copyright"
+);
+text_meta_event!(
+    /// FF 03 sequence or track name
+    TrackName, 0x03, "TrackName"
+);
+text_meta_event!(
+    /// FF 04 instrument name
+    InstrumentName, 0x04, "InstrumentName"
+);
+text_meta_event!(
+    /// FF 05 lyric, usually one syllable
+    Lyric, 0x05, "Lyric"
+);
+text_meta_event!(
+    /// FF 06 marker, such as a rehearsal letter or section name
+    Marker, 0x06, "Marker"
+);
+text_meta_event!(
+    /// FF 07 cue point
+    CuePoint, 0x07, "CuePoint"
+);
+
+/// FF 58 time signature
+#[derive(Debug, Clone)]
+pub struct TimeSignature {
+    time: u32,
+    numerator: u8,
+    /// Power of two: 2 is a quarter note
+    denominator: u8,
+}
+
+impl TimeSignature {
+    /// `numerator / denominator`, e.g. `3, 4` for 3/4; the denominator is
+    /// rounded down to a power of two
+    pub fn new(numerator: u8, denominator: u8) -> Self {
+        TimeSignature { time: 0, numerator, denominator: denominator.max(1).ilog2() as u8 }
+    }
+}
+
+impl TrackEvent for TimeSignature {
+    fn lead_time(&self) -> u32 { self.time }
+    fn set_lead_time(&mut self, time: u32) { self.time = time; }
+
+    fn value(&self) -> String {
+        format!("TimeSignature: {}/{}", self.numerator, 1u32 << self.denominator)
+    }
+
+    fn to_buffer(&self) -> ByteBuffer {
+        let mut buffer = ByteBuffer::new();
+        buffer.put_byte(0xFF);
+        buffer.put_byte(0x58);
+        buffer.put_byte(0x04);
+        buffer.put_byte(self.numerator);
+        buffer.put_byte(self.denominator);
+        buffer.put_byte(24); // MIDI clocks per metronome click
+        buffer.put_byte(8);  // 32nd notes per quarter note
+        buffer
+    }
+
+    fn clone_event(&self) -> Box<dyn TrackEvent> {
+        Box::new(self.clone())
+    }
+}
+
+/// FF 59 key signature
+#[derive(Debug, Clone)]
+pub struct KeySignature {
+    time: u32,
+    /// Sharps when positive, flats when negative
+    sharps: i8,
+    minor: bool,
+}
+
+impl KeySignature {
+    /// `sharps` from -7 (seven flats) to 7 (seven sharps)
+    pub fn new(sharps: i8, minor: bool) -> Self {
+        KeySignature { time: 0, sharps: sharps.clamp(-7, 7), minor }
+    }
+}
+
+impl TrackEvent for KeySignature {
+    fn lead_time(&self) -> u32 { self.time }
+    fn set_lead_time(&mut self, time: u32) { self.time = time; }
+
+    fn value(&self) -> String {
+        format!("KeySignature: sharps={}, {}", self.sharps, if self.minor { "minor" } else { "major" })
+    }
+
+    fn to_buffer(&self) -> ByteBuffer {
+        let mut buffer = ByteBuffer::new();
+        buffer.put_byte(0xFF);
+        buffer.put_byte(0x59);
+        buffer.put_byte(0x02);
+        buffer.put_byte(self.sharps as u8);
+        buffer.put_byte(self.minor as u8);
+        buffer
+    }
+
+    fn clone_event(&self) -> Box<dyn TrackEvent> {
+        Box::new(self.clone())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Tempo {
     time: u32,
@@ -138,7 +338,7 @@ impl TrackEvent for SysEx {
     fn to_buffer(&self) -> ByteBuffer {
         let mut buffer = ByteBuffer::new();
         buffer.put_byte(0xF0);
-        buffer.put_byte(self.data.len() as u8);
+        buffer.put_var_len(self.data.len() as u32);
         buffer.put_bytes_array(&self.data);
         buffer
     }
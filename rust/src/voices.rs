@@ -0,0 +1,105 @@
+//! Splits polyphonic notes into monophonic lines for MML parts
+
+use crate::constants::timing;
+use std::cmp::Reverse;
+
+/// A note that held on past the start of the next by no more than this
+/// counts as legato, not as a second voice: a sixteenth note
+pub(crate) const LEGATO_OVERLAP: u32 = timing::TICKS_PER_QUARTER_NOTE / 4;
+
+/// Distance charged for starting a fresh line, an octave, so a voice moves
+/// on in its own line unless it leaps further
+const NEW_LINE_DISTANCE: u32 = 12;
+
+/// A sounding note, timed at 96 ticks per quarter note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub start: u32,
+    pub end: u32,
+    /// MIDI note number
+    pub pitch: u8,
+    pub velocity: u8,
+}
+
+/// Which line each note of a chord goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStrategy {
+    /// Line n takes the nth highest note of each chord, cutting short
+    /// whatever it held, so a held bass note ends where a higher note
+    /// starts; notes below the last line are dropped. `MidiToMml` lists the
+    /// cut notes in `ImportReport::shortened`.
+    #[default]
+    Highest,
+    /// Each note joins the line whose last pitch is nearest, preferring
+    /// lines that are free over cutting one short
+    Proximity,
+    /// The first line takes the top note of each chord and the last line
+    /// the bottom one; the notes in between are dropped
+    OuterVoices,
+}
+
+/// Splits `notes` into at most `voices` lines that each play one note at a
+/// time, returning the lines that have notes and the notes that fit in none
+///
+/// A note still sounding when its line takes the next is cut short there.
+/// Notes repeating the pitch and start of another are dropped.
+pub fn separate(notes: &[Note], voices: usize, strategy: VoiceStrategy) -> (Vec<Vec<Note>>, Vec<Note>) {
+    let voices = voices.max(1);
+    let mut notes = notes.to_vec();
+    notes.sort_by_key(|note| (note.start, Reverse(note.pitch)));
+
+    let mut lines: Vec<Vec<Note>> = vec![Vec::new(); voices];
+    let mut dropped = Vec::new();
+    for chord in notes.chunk_by(|a, b| a.start == b.start) {
+        let mut chord = chord.to_vec();
+        chord.dedup_by(|repeat, kept| {
+            let duplicate = repeat.pitch == kept.pitch;
+            if duplicate {
+                dropped.push(*repeat);
+            }
+            duplicate
+        });
+
+        let start = chord[0].start;
+        let mut chosen: Vec<(Note, usize)> = Vec::with_capacity(voices);
+        match strategy {
+            VoiceStrategy::Highest => {
+                chosen.extend(chord.iter().copied().zip(0..voices));
+            }
+            VoiceStrategy::OuterVoices => {
+                chosen.push((chord[0], 0));
+                if voices > 1 && chord.len() > 1 {
+                    chosen.push((chord[chord.len() - 1], voices - 1));
+                }
+            }
+            VoiceStrategy::Proximity => {
+                for &note in chord.iter().take(voices) {
+                    let line = (0..voices)
+                        .filter(|line| chosen.iter().all(|&(_, taken)| taken != *line))
+                        .min_by_key(|&line| match lines[line].last() {
+                            None => (false, NEW_LINE_DISTANCE, line),
+                            Some(last) => (
+                                last.end > start + LEGATO_OVERLAP,
+                                last.pitch.abs_diff(note.pitch) as u32,
+                                line,
+                            ),
+                        });
+                    if let Some(line) = line {
+                        chosen.push((note, line));
+                    }
+                }
+            }
+        }
+
+        dropped.extend(chord.iter().filter(|note| chosen.iter().all(|(kept, _)| kept != *note)));
+        for (note, line) in chosen {
+            if let Some(last) = lines[line].last_mut() {
+                last.end = last.end.min(note.start);
+            }
+            lines[line].push(note);
+        }
+    }
+
+    lines.retain(|line| !line.is_empty());
+    (lines, dropped)
+}
@@ -1,7 +1,15 @@
 use crate::byte_buffer::ByteBuffer;
-use crate::mf2tt2mf::Mf2tt2mf;
+use crate::channel_allocator::ChannelAllocator;
+use crate::conductor;
+use crate::mf2tt2mf::{header_events, Mf2tt2mf};
 use crate::errors::ConversionError;
+use crate::instruments::Instrument;
+use crate::mml::parser;
+use crate::options::{ConversionOptions, OutputFormat};
+use crate::part_settings::PartSettings;
+use crate::report::{ConversionReport, ConversionWarning};
 use crate::constants::{timing, midi};
+use std::collections::HashMap;
 
 const START_TIMEBASE: u16 = timing::DEFAULT_TIMEBASE;
 
@@ -9,6 +17,11 @@ pub struct YksConverter {
     mml: Vec<String>,
     inst: Vec<u8>,
     timebase: u16,
+    options: ConversionOptions,
+    settings: HashMap<usize, PartSettings>,
+    part_settings: HashMap<(usize, usize), PartSettings>,
+    names: HashMap<usize, String>,
+    part_names: HashMap<(usize, usize), String>,
 }
 
 impl YksConverter {
@@ -17,6 +30,11 @@ impl YksConverter {
             mml: vec![mml],
             inst: vec![inst],
             timebase: START_TIMEBASE,
+            options: ConversionOptions::default(),
+            settings: HashMap::new(),
+            part_settings: HashMap::new(),
+            names: HashMap::new(),
+            part_names: HashMap::new(),
         }
     }
 
@@ -25,6 +43,11 @@ impl YksConverter {
             mml,
             inst,
             timebase: START_TIMEBASE,
+            options: ConversionOptions::default(),
+            settings: HashMap::new(),
+            part_settings: HashMap::new(),
+            names: HashMap::new(),
+            part_names: HashMap::new(),
         }
     }
 
@@ -44,6 +67,67 @@ impl YksConverter {
         self.inst = inst;
     }
 
+    pub fn set_options(&mut self, options: ConversionOptions) {
+        self.options = options;
+    }
+
+    /// Ticks per quarter note in the MIDI file, 96 by default
+    ///
+    /// Lengths, tuplets and the lead-in before the first note scale with it,
+    /// so the music plays the same at any timebase. Must be 1-32767.
+    pub fn set_timebase(&mut self, timebase: u16) {
+        self.timebase = timebase;
+    }
+
+    pub fn timebase(&self) -> u16 {
+        self.timebase
+    }
+
+    /// Instrument for MML string `index`, keeping its other settings
+    ///
+    /// Notes outside the instrument's range are reported as warnings. The
+    /// drum kit plays on the percussion channel.
+    pub fn set_instrument(&mut self, index: usize, instrument: Instrument) {
+        if let Some(inst) = self.inst.get_mut(index) {
+            *inst = instrument.program();
+        }
+        let settings = self.settings.get(&index).cloned().unwrap_or_default();
+        self.settings.insert(index, settings.with_instrument(instrument));
+    }
+
+    /// Instrument and mixer settings for every part of MML string `index`
+    ///
+    /// An instrument of `None` keeps the one given with the MML.
+    pub fn set_settings(&mut self, index: usize, settings: PartSettings) {
+        self.settings.insert(index, settings);
+    }
+
+    /// Settings for one part of MML string `index`, replacing the string's
+    ///
+    /// A part whose settings differ from its string's plays on a channel of
+    /// its own, since program and controllers apply to a whole channel. Drum
+    /// parts all play on the percussion channel.
+    pub fn set_part_settings(&mut self, index: usize, part: usize, settings: PartSettings) {
+        self.part_settings.insert((index, part), settings);
+    }
+
+    /// Performer name for MML string `index`; its tracks are named
+    /// "<name> - Part 1" and so on
+    pub fn set_name(&mut self, index: usize, name: String) {
+        self.names.insert(index, name);
+    }
+
+    /// Name for one part of MML string `index`, replacing "Part 1" and so on
+    pub fn set_part_name(&mut self, index: usize, part: usize, name: String) {
+        self.part_names.insert((index, part), name);
+    }
+
+    /// Settings of MML string `index`, with its instrument filled in
+    pub fn settings(&self, index: usize) -> PartSettings {
+        let settings = self.settings.get(&index).cloned().unwrap_or_default();
+        settings.or_instrument(self.inst.get(index).copied().unwrap_or_default())
+    }
+
     pub fn mml(&self) -> &[String] {
         &self.mml
     }
@@ -52,6 +136,10 @@ impl YksConverter {
         &self.inst
     }
 
+    pub fn options(&self) -> &ConversionOptions {
+        &self.options
+    }
+
     /// Converts MML to MIDI buffer
     /// 
     /// # Returns
@@ -67,6 +155,24 @@ impl YksConverter {
     /// let buffer = converter.to_buffer_result().unwrap();
     /// ```
     pub fn to_buffer_result(&self) -> Result<ByteBuffer, ConversionError> {
+        self.to_buffer_with_report().map(|(buffer, _)| buffer)
+    }
+
+    /// Converts MML to MIDI buffer and reports tokens that did not play as written
+    ///
+    /// The report lists every value that was clamped, ignored or coerced, with
+    /// the MML index, track and byte offset it came from.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use yks_converter::YksConverter;
+    ///
+    /// let converter = YksConverter::new("MML@v20c,,;".to_string(), 1);
+    /// let (_buffer, report) = converter.to_buffer_with_report().unwrap();
+    /// assert_eq!(report.warnings[0].diagnostic.text, "v20");
+    /// ```
+    pub fn to_buffer_with_report(&self) -> Result<(ByteBuffer, ConversionReport), ConversionError> {
         if self.mml.len() != self.inst.len() {
             return Err(ConversionError::MmlInstCountMismatch {
                 mml_count: self.mml.len(),
@@ -74,6 +180,119 @@ impl YksConverter {
             });
         }
 
+        if !(1..=timing::MAX_TIMEBASE).contains(&self.timebase) {
+            return Err(ConversionError::InvalidTimebase(self.timebase));
+        }
+
+        let mut tracks = Vec::new();
+        let mut report = ConversionReport::new();
+        let mut channels = ChannelAllocator::new();
+
+        for (i, mml) in self.mml.iter().enumerate() {
+            let score = parser::parse_with(mml, &self.options.dialect).map_err(|diagnostics| {
+                ConversionError::MmlParseFailed { index: i, diagnostics }
+            })?;
+
+            let settings = self.settings(i);
+            let exhausted = |settings: &PartSettings| ConversionError::ChannelsExhausted {
+                index: i,
+                instrument: settings.instrument.unwrap_or(self.inst[i]),
+            };
+            let channel = if settings.percussion.is_some() {
+                channels.percussion()
+            } else {
+                channels.allocate_for(&settings).ok_or_else(|| exhausted(&settings))?
+            };
+            let mut mf2tt2mf = Mf2tt2mf::new(channel, self.inst[i], 64, 0);
+            mf2tt2mf.set_options(self.options.clone());
+            mf2tt2mf.set_timebase(self.timebase);
+            mf2tt2mf.set_settings(settings.clone());
+            mf2tt2mf.set_writes_header(i == 0 && self.options.format == OutputFormat::Parts);
+
+            for part in 0..score.tracks.len() {
+                let Some(part_settings) = self.part_settings.get(&(i, part)) else {
+                    continue;
+                };
+                let part_settings = part_settings.or_instrument(self.inst[i]);
+                let part_channel = if part_settings == settings {
+                    channel
+                } else if part_settings.percussion.is_some() {
+                    channels.percussion()
+                } else {
+                    channels.allocate_for(&part_settings).ok_or_else(|| exhausted(&part_settings))?
+                };
+                mf2tt2mf.set_part_settings(part, part_channel, part_settings);
+            }
+
+            for part in 0..score.tracks.len() {
+                let part_name = self.part_names.get(&(i, part));
+                if !self.options.track_names && part_name.is_none() && !self.names.contains_key(&i) {
+                    continue;
+                }
+                let performer = self.names.get(&i).cloned().unwrap_or_else(|| format!("MML {}", i + 1));
+                let part_name = part_name.cloned().unwrap_or_else(|| format!("Part {}", part + 1));
+                mf2tt2mf.set_track_name(part, format!("{} - {}", performer, part_name));
+            }
+
+            mf2tt2mf.from_score(mml, &score);
+            if self.options.strict && !mf2tt2mf.warnings().is_empty() {
+                return Err(ConversionError::StrictModeViolation {
+                    index: i,
+                    diagnostics: mf2tt2mf.warnings().to_vec(),
+                });
+            }
+            report.warnings.extend(mf2tt2mf.warnings().iter().map(|diagnostic| ConversionWarning {
+                index: i,
+                diagnostic: diagnostic.clone(),
+            }));
+
+            tracks.extend(mf2tt2mf.build());
+        }
+
+        let (format, tracks) = match self.options.format {
+            OutputFormat::Parts => (midi::FORMAT_TYPE, tracks),
+            OutputFormat::Conductor => {
+                (midi::FORMAT_TYPE, conductor::with_conductor(header_events(&self.options), tracks, self.options.tempo_policy))
+            }
+            OutputFormat::SingleTrack => {
+                (midi::SINGLE_TRACK_FORMAT, vec![conductor::merged(header_events(&self.options), tracks, self.options.tempo_policy)])
+            }
+        };
+
+        let mut track_chunks = ByteBuffer::new();
+        for event_list in &tracks {
+            let mut time = 0u32;
+            let mut last = 0x00u8;
+            let mut track_buffer = ByteBuffer::new();
+
+            for event in event_list {
+                let delta_time = event.lead_time() - time;
+                time = event.lead_time();
+                
+                track_buffer.put_var_len(delta_time);
+
+                let event_buffer = event.to_buffer();
+                if event_buffer.size() == 0 {
+                    return Err(ConversionError::EventConversionFailed(event.value()));
+                }
+
+                let start = event_buffer.get_at(0);
+                if !(0x80..=0xef).contains(&start) || start != last {
+                    track_buffer.put_byte(start);
+                }
+                
+                for i in 1..event_buffer.size() {
+                    track_buffer.put_byte(event_buffer.get_at(i));
+                }
+                last = start;
+            }
+
+            track_chunks.put_string(midi::TRACK_CHUNK);
+            let track_length = track_buffer.size() as u32;
+            track_chunks.put_u32(track_length);
+            track_chunks.put_bytes(&track_buffer);
+        }
+
         let mut byte_buffer = ByteBuffer::new();
 
         // Header Chunk
@@ -81,60 +300,18 @@ impl YksConverter {
         byte_buffer.put_string(midi::HEADER_CHUNK);
         byte_buffer.put_bytes_array(&default_buffer);
         
-        // format: 1 (multiple tracks)
-        byte_buffer.put_byte(midi::FORMAT_TYPE as u8);
+        // format: 1 (multiple tracks) or 0 (one merged track)
+        byte_buffer.put_byte(format as u8);
         
-        // track count (2 bytes)
-        byte_buffer.put_u16((self.mml.len() as u16) * midi::TRACKS_PER_MML);
+        // track count (2 bytes), one per part actually present
+        byte_buffer.put_u16(tracks.len() as u16);
         
         // timebase (2 bytes)  
         byte_buffer.put_u16(self.timebase);
 
-        for (i, mml) in self.mml.iter().enumerate() {
-            let mut mf2tt2mf = Mf2tt2mf::new((i + 1) as u8, self.inst[i], 64, 0);
-            
-            if !mf2tt2mf.from_mml(mml) {
-                return Err(ConversionError::MmlParseFailed(mml.clone()));
-            }
-
-            let track_event_list = mf2tt2mf.build();
-
-            for event_list in track_event_list {
-                let mut time = 0u32;
-                let mut last = 0x00u8;
-                let mut track_buffer = ByteBuffer::new();
-
-                for event in event_list {
-                    let delta_time = event.lead_time() - time;
-                    time = event.lead_time();
-                    
-                    let var_len_buffer = write_var_len(delta_time);
-                    track_buffer.put_bytes(&var_len_buffer);
-
-                    let event_buffer = event.to_buffer();
-                    if event_buffer.size() <= 0 {
-                        return Err(ConversionError::EventConversionFailed(event.value()));
-                    }
-
-                    let start = event_buffer.get_at(0);
-                    if start < 0x80 || start > 0xef || start != last {
-                        track_buffer.put_byte(start);
-                    }
-                    
-                    for i in 1..event_buffer.size() {
-                        track_buffer.put_byte(event_buffer.get_at(i));
-                    }
-                    last = start;
-                }
-
-                byte_buffer.put_string(midi::TRACK_CHUNK);
-                let track_length = track_buffer.size() as u32;
-                byte_buffer.put_u32(track_length);
-                byte_buffer.put_bytes(&track_buffer);
-            }
-        }
+        byte_buffer.put_bytes(&track_chunks);
 
-        Ok(byte_buffer)
+        Ok((byte_buffer, report))
     }
 
     /// Legacy method for backward compatibility
@@ -151,26 +328,3 @@ impl YksConverter {
         }
     }
 }
-
-fn write_var_len(mut value: u32) -> ByteBuffer {
-    let mut buf = (value & 0x7f) as u32;
-    let mut byte_buffer = ByteBuffer::new();
-
-    value >>= 7;
-    while value > 0 {
-        buf <<= 8;
-        buf |= (value & 0x7f) | 0x80;
-        value >>= 7;
-    }
-
-    loop {
-        byte_buffer.put_byte((buf % 256) as u8);
-        if (buf & 0x80) != 0 {
-            buf >>= 8;
-        } else {
-            break;
-        }
-    }
-
-    byte_buffer
-}